@@ -0,0 +1,503 @@
+//! imagedrive_api embed imagedrive drives in Rust programs, instead of running the CLI.
+//!
+//! A drive is opened with `Drive::builder`, entries and items are handles which are
+//! listed with iterators, and items are read and written with `std::io`, through temp files:
+//!
+//! ```no_run
+//! use imagedrive_api::{Conflict, Drive};
+//! use std::io::{Read, Write};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let drive = Drive::builder("registry.example.com/team/datasets:latest").build()?;
+//! for entry in drive.entries()? {
+//!     for item in entry.items()? {
+//!         println!("{}/{} {:?}", entry.name(), item.name(), item.info().size);
+//!     }
+//! }
+//! let entry = drive.entry("reports");
+//! let mut w = entry.writer("today.txt", Conflict::Overwrite)?;
+//! w.write_all(b"all green\n")?;
+//! w.commit()?;
+//! let mut content = String::new();
+//! entry.item("today.txt").reader()?.read_to_string(&mut content)?;
+//! drive.sync(Some("add today's report"))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Changes stay in the local drive until `Drive::sync`, like with the CLI.
+//! Everything else of the `DB` trait is reachable with `Drive::db`
+
+use db::docker::ImageDrive;
+use db::metacache::MetaCache;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub use db::cipher::Cipher;
+pub use db::codec::Compression;
+pub use db::docker::Error;
+pub use db::hash::Algorithm;
+pub use db::{AddResult, Change, Conflict, ItemInfo, RemoteState, Revision, Status, DB};
+pub use dockerclient::Progress;
+
+/// DriveBuilder configure a drive before it is opened, see `Drive::builder`
+pub struct DriveBuilder {
+    image: String,
+    login: Option<(String, String, String)>,
+    cipher: Option<Cipher>,
+    compression: Option<Compression>,
+    chunk_threshold: Option<u64>,
+    jobs: Option<usize>,
+    algorithm: Option<Algorithm>,
+    hash_cache: Option<PathBuf>,
+    meta_cache: Option<PathBuf>,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl DriveBuilder {
+    /// login to `server` before a push or pull fails for lack of it.
+    /// Without it the credentials docker keeps are used, e.g. from `docker login`
+    pub fn login(mut self, server: &str, username: &str, password: &str) -> Self {
+        self.login = Some((server.to_owned(), username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// encrypt items added later with `cipher`, and decrypt items read
+    pub fn cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// compress items added later
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// store files bigger than `threshold` bytes as deduplicated chunks
+    pub fn chunk_threshold(mut self, threshold: u64) -> Self {
+        self.chunk_threshold = Some(threshold);
+        self
+    }
+
+    /// hash and read checksums on up to `jobs` threads, default the number of cpus
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// checksum algorithm of items added later, default sha256
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = Some(algorithm);
+        self
+    }
+
+    /// remember checksums of host files in the file `path`, so unchanged files aren't read again
+    pub fn hash_cache<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.hash_cache = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// cache the index of the drive in the sqlite db `path`, it is refreshed by
+    /// `Drive::refresh_cache`
+    pub fn meta_cache<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.meta_cache = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// report transfers to `progress`
+    pub fn progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// build open the drive, the docker daemon is only reached by the first use of it.
+    /// Its socket must exist though
+    pub fn build(self) -> Result<Drive, Error> {
+        let mut drive = match &self.login {
+            Some((server, username, password)) => {
+                ImageDrive::new(&self.image, server, username, password)
+            }
+            None => ImageDrive::open(&self.image),
+        };
+        if let Some(cipher) = self.cipher {
+            drive = drive.with_cipher(cipher);
+        }
+        if let Some(compression) = self.compression {
+            drive = drive.with_compression(compression);
+        }
+        if let Some(threshold) = self.chunk_threshold {
+            drive = drive.with_chunk_threshold(threshold);
+        }
+        if let Some(jobs) = self.jobs {
+            drive = drive.with_jobs(jobs);
+        }
+        if let Some(algorithm) = self.algorithm {
+            drive = drive.with_algorithm(algorithm);
+        }
+        if let Some(path) = self.hash_cache {
+            drive = drive.with_hash_cache(&path);
+        }
+        if let Some(path) = self.meta_cache {
+            let cache = MetaCache::open(&path).map_err(|e| Error::MetaCacheError(e.to_string()))?;
+            drive = drive.with_meta_cache(cache);
+        }
+        if let Some(progress) = self.progress {
+            drive = drive.with_progress(progress);
+        }
+        Ok(Drive { inner: drive })
+    }
+}
+
+/// Drive is an imagedrive drive, the docker image `image` and its local container
+pub struct Drive {
+    inner: ImageDrive,
+}
+
+impl Drive {
+    /// builder of the drive of docker image `image`, like `registry/team/drive:latest`
+    pub fn builder(image: &str) -> DriveBuilder {
+        DriveBuilder {
+            image: image.to_owned(),
+            login: None,
+            cipher: None,
+            compression: None,
+            chunk_threshold: None,
+            jobs: None,
+            algorithm: None,
+            hash_cache: None,
+            meta_cache: None,
+            progress: None,
+        }
+    }
+
+    /// db is the drive as a `DB`, for what the handles don't cover
+    pub fn db(&self) -> &ImageDrive {
+        &self.inner
+    }
+
+    /// entries of the drive
+    pub fn entries(&self) -> Result<impl Iterator<Item = Entry<'_>>, Error> {
+        Ok(self
+            .inner
+            .entries()?
+            .into_iter()
+            .map(move |name| Entry { drive: self, name }))
+    }
+
+    /// entry `name`, which is made by writing an item to it if it doesn't exist
+    pub fn entry(&self, name: &str) -> Entry<'_> {
+        Entry {
+            drive: self,
+            name: name.to_owned(),
+        }
+    }
+
+    /// find items whose name matches `pattern`, a glob with `*` and `?` or a substring
+    pub fn find(&self, pattern: &str) -> Result<impl Iterator<Item = Item<'_>>, Error> {
+        Ok(self
+            .inner
+            .find(pattern)?
+            .into_iter()
+            .map(move |(entry, info)| Item {
+                drive: self,
+                entry,
+                name: info.name.clone(),
+                info: Some(info),
+            }))
+    }
+
+    /// sync commit local changes as a revision with `message` and push them,
+    /// or pull the drive if it isn't local
+    pub fn sync(&self, message: Option<&str>) -> Result<(), Error> {
+        self.inner.sync(message)
+    }
+
+    /// status is the changes not synced yet
+    pub fn status(&self) -> Result<Status, Error> {
+        self.inner.status()
+    }
+
+    /// log is the revisions of the drive, oldest first
+    pub fn log(&self) -> Result<Vec<Revision>, Error> {
+        self.inner.log()
+    }
+
+    /// refresh_cache refresh the index cached by `DriveBuilder::meta_cache`
+    pub fn refresh_cache(&self) -> Result<(), Error> {
+        self.inner.refresh_cache()
+    }
+}
+
+/// Entry is a handle of an entry of a drive, it may not exist yet
+pub struct Entry<'a> {
+    drive: &'a Drive,
+    name: String,
+}
+
+impl<'a> Entry<'a> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// items of the entry with their metadata
+    pub fn items(&self) -> Result<impl Iterator<Item = Item<'a>>, Error> {
+        let drive = self.drive;
+        let entry = self.name.clone();
+        Ok(drive
+            .inner
+            .item_infos(&self.name)?
+            .into_iter()
+            .map(move |info| Item {
+                drive,
+                entry: entry.clone(),
+                name: info.name.clone(),
+                info: Some(info),
+            }))
+    }
+
+    /// item `name` of the entry, which may not exist
+    pub fn item(&self, name: &str) -> Item<'a> {
+        Item {
+            drive: self.drive,
+            entry: self.name.clone(),
+            name: name.to_owned(),
+            info: None,
+        }
+    }
+
+    /// put add the file or dir at `path` to the entry, under `name` if given
+    pub fn put(
+        &self,
+        path: &Path,
+        name: Option<&str>,
+        conflict: Conflict,
+    ) -> Result<AddResult, Error> {
+        self.drive.inner.add(&self.name, path, name, conflict)
+    }
+
+    /// writer of a new item `name`, which is added when the writer is committed
+    pub fn writer(&self, name: &str, conflict: Conflict) -> Result<ItemWriter<'a>, Error> {
+        if !db::docker::valid_item_name(name) {
+            return Err(Error::BadItemName(name.to_owned()));
+        }
+        let dir = tempfile::tempdir().map_err(Error::IOError)?;
+        let path = dir.path().join(name);
+        let file = File::create(&path).map_err(Error::IOError)?;
+        Ok(ItemWriter {
+            entry: self.drive.entry(&self.name),
+            name: name.to_owned(),
+            conflict,
+            file,
+            path,
+            _dir: dir,
+        })
+    }
+
+    /// export the entry to host dir `dir`
+    pub fn export(&self, dir: &Path) -> Result<(), Error> {
+        self.drive.inner.export_to_dir(dir, &self.name)
+    }
+
+    /// delete the entry, its items are moved to the trash
+    pub fn delete(self) -> Result<(), Error> {
+        self.drive.inner.delete(&self.name, None)
+    }
+}
+
+/// Item is a handle of an item of an entry
+pub struct Item<'a> {
+    drive: &'a Drive,
+    entry: String,
+    name: String,
+    info: Option<ItemInfo>,
+}
+
+impl<'a> Item<'a> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn entry(&self) -> Entry<'a> {
+        self.drive.entry(&self.entry)
+    }
+
+    /// info is the metadata of the item as listed, without size and checksum
+    /// for a handle made by `Entry::item`
+    pub fn info(&self) -> ItemInfo {
+        self.info.clone().unwrap_or_else(|| ItemInfo {
+            name: self.name.clone(),
+            checksum: None,
+            size: None,
+            stored: None,
+            dir: false,
+        })
+    }
+
+    /// read_to write the content of the item to `out`
+    pub fn read_to(&self, out: &mut dyn Write) -> Result<(), Error> {
+        self.drive.inner.cat(&self.entry, &self.name, out)
+    }
+
+    /// reader of the content of the item. The whole content is read out of the container
+    /// into a temp file before it returns, so a failed read never yields half of it.
+    /// It is not a stream: the first byte waits for the last one, and a big item
+    /// takes as much temp space as its size
+    pub fn reader(&self) -> Result<ItemReader, Error> {
+        let mut file = tempfile::tempfile().map_err(Error::IOError)?;
+        self.read_to(&mut file)?;
+        file.seek(SeekFrom::Start(0)).map_err(Error::IOError)?;
+        Ok(ItemReader { file })
+    }
+
+    /// rename the item to `new_name` in its entry
+    pub fn rename(self, new_name: &str) -> Result<Item<'a>, Error> {
+        self.drive.inner.rename(&self.entry, &self.name, new_name)?;
+        Ok(Item {
+            drive: self.drive,
+            entry: self.entry,
+            name: new_name.to_owned(),
+            info: self.info.map(|info| ItemInfo {
+                name: new_name.to_owned(),
+                ..info
+            }),
+        })
+    }
+
+    /// delete the item, it is moved to the trash
+    pub fn delete(self) -> Result<(), Error> {
+        self.drive.inner.delete(&self.entry, Some(&self.name))
+    }
+}
+
+/// ItemReader read the content of an item spooled to a temp file, see `Item::reader`
+pub struct ItemReader {
+    file: File,
+}
+
+impl Read for ItemReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for ItemReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// ItemWriter write a new item, which is added by `commit`.
+/// A writer dropped without commit adds nothing
+pub struct ItemWriter<'a> {
+    entry: Entry<'a>,
+    name: String,
+    conflict: Conflict,
+    file: File,
+    path: PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl<'a> ItemWriter<'a> {
+    /// commit add what is written as the item
+    pub fn commit(mut self) -> Result<AddResult, Error> {
+        self.file.flush().map_err(Error::IOError)?;
+        self.entry.put(&self.path, Some(&self.name), self.conflict)
+    }
+}
+
+impl<'a> Write for ItemWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// builder of a drive without docker: the socket only has to exist to build a drive,
+    /// and nothing answers on this one
+    fn offline(image: &str) -> DriveBuilder {
+        static OFFLINE: std::sync::Once = std::sync::Once::new();
+        OFFLINE.call_once(|| std::env::set_var("DOCKER_HOST", "unix:///dev/null"));
+        Drive::builder(image)
+    }
+
+    #[test]
+    fn builder() {
+        let b = offline("drive:latest")
+            .login("registry.example.com", "me", "secret")
+            .jobs(2)
+            .chunk_threshold(1024)
+            .algorithm(Algorithm::Blake3)
+            .hash_cache("/tmp/hashes");
+        assert_eq!(b.image, "drive:latest");
+        assert_eq!(
+            b.login,
+            Some((
+                "registry.example.com".to_owned(),
+                "me".to_owned(),
+                "secret".to_owned()
+            ))
+        );
+        assert_eq!(b.jobs, Some(2));
+        assert_eq!(b.algorithm, Some(Algorithm::Blake3));
+        assert_eq!(b.chunk_threshold, Some(1024));
+        assert_eq!(b.hash_cache, Some(PathBuf::from("/tmp/hashes")));
+        assert!(b.cipher.is_none() && b.meta_cache.is_none() && b.progress.is_none());
+        // docker isn't reached until the drive is used
+        assert!(b.build().is_ok());
+    }
+
+    #[test]
+    fn builder_opens_meta_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.db");
+        assert!(offline("drive").meta_cache(&path).build().is_ok());
+        assert!(path.exists());
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        match offline("drive").meta_cache(file.join("index.db")).build() {
+            Err(Error::MetaCacheError(_)) => (),
+            r => panic!("{:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn writer_spools_until_commit() {
+        let drive = offline("drive").build().unwrap();
+        let entry = drive.entry("e");
+        let mut w = entry.writer("a.txt", Conflict::NoClobber).unwrap();
+        w.write_all(b"hello ").unwrap();
+        w.write_all(b"world").unwrap();
+        w.flush().unwrap();
+        assert_eq!(w.path.file_name().unwrap(), "a.txt");
+        assert_eq!(std::fs::read(&w.path).unwrap(), b"hello world");
+        // dropped without commit, nothing is left behind
+        let spool = w.path.clone();
+        drop(w);
+        assert!(!spool.exists());
+        assert!(!spool.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn writer_refuses_bad_names() {
+        let drive = offline("drive").build().unwrap();
+        let entry = drive.entry("e");
+        for name in &["", ".", "..", "a/b"] {
+            match entry.writer(name, Conflict::NoClobber) {
+                Err(Error::BadItemName(n)) => assert_eq!(&n, name),
+                Err(e) => panic!("{}: {}", name, e),
+                Ok(_) => panic!("{} is accepted", name),
+            }
+        }
+    }
+}
@@ -9,6 +9,21 @@ pub struct Config {
     pub username: String,
     pub password: String,
     pub image_name: String,
+    /// hex encoded 32 bytes key, items are encrypted if it or `keyfile` is set
+    pub encryption_key: Option<String>,
+    /// file holding the key, 32 raw bytes or 64 hex chars
+    pub keyfile: Option<String>,
+    /// encrypt entry and item names too
+    #[serde(default)]
+    pub encrypt_names: bool,
+    /// compress items added later, `gzip` or `zstd`
+    pub compression: Option<String>,
+    /// files bigger than this many bytes are stored as deduplicated chunks, default 64 MiB
+    pub chunk_threshold: Option<u64>,
+    /// checksum algorithm of items added later, `sha256` (default) or `blake3`
+    pub hash: Option<String>,
+    /// threads hashing files and reading checksums, default the number of cpus
+    pub jobs: Option<usize>,
 }
 
 pub fn get_config<P: AsRef<std::path::Path>>(path: P) -> Config {
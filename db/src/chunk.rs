@@ -0,0 +1,331 @@
+use crate::codec::Codec;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// chunks are stored in the drive at `CHUNKS_DIR/<first 2 chars of id>/<id>`
+pub const CHUNKS_DIR: &str = "/chunks";
+/// a chunked file is replaced in the drive by its recipe, which starts with MAGIC
+const MAGIC: &str = "IDCHUNKS1\n";
+
+/// files bigger than this are chunked, unless configured
+pub const DEFAULT_THRESHOLD: u64 = 64 * 1024 * 1024;
+const MIN_SIZE: u32 = 256 * 1024;
+const AVG_SIZE: u32 = 1024 * 1024;
+const MAX_SIZE: u32 = 4 * 1024 * 1024;
+
+/// ChunkRef is a chunk in a recipe
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkRef {
+    pub id: String,
+    /// original length
+    pub len: u64,
+    /// length after compression and encryption
+    pub stored: u64,
+}
+
+/// Recipe list the chunks of a file in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipe {
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+}
+
+impl Recipe {
+    pub fn format(&self) -> String {
+        let mut s = format!("{}{}\n", MAGIC, self.size);
+        for c in &self.chunks {
+            s.push_str(&format!("{} {} {}\n", c.id, c.len, c.stored));
+        }
+        s
+    }
+
+    pub fn parse(content: &[u8]) -> Option<Recipe> {
+        if !is_recipe(content) {
+            return None;
+        }
+        let content = std::str::from_utf8(&content[MAGIC.len()..]).ok()?;
+        let mut lines = content.lines();
+        let size = lines.next()?.parse().ok()?;
+        let mut chunks = vec![];
+        for line in lines {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() != 3 {
+                return None;
+            }
+            chunks.push(ChunkRef {
+                id: fields[0].to_owned(),
+                len: fields[1].parse().ok()?,
+                stored: fields[2].parse().ok()?,
+            });
+        }
+        Some(Recipe { size, chunks })
+    }
+
+    /// paths of chunks inside the drive
+    pub fn paths(&self) -> Vec<String> {
+        self.chunks.iter().map(|c| chunk_path(&c.id)).collect()
+    }
+}
+
+/// is_recipe tell if `header`, the first bytes of a stored file, is a recipe
+pub fn is_recipe(header: &[u8]) -> bool {
+    header.starts_with(MAGIC.as_bytes())
+}
+
+pub fn chunk_path(id: &str) -> String {
+    format!("{}/{}/{}", CHUNKS_DIR, &id[..2], id)
+}
+
+/// recipe_ids list ids of chunks which recipes among the files at `path`, a file or a
+/// directory, refer to
+pub fn recipe_ids(path: &Path) -> io::Result<Vec<String>> {
+    let mut ids = vec![];
+    for e in walkdir::WalkDir::new(path) {
+        let e = e.map_err(io::Error::from)?;
+        if !e.file_type().is_file() {
+            continue;
+        }
+        let mut content = vec![];
+        let mut f = std::fs::File::open(e.path())?;
+        (&mut f)
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut content)?;
+        if !is_recipe(&content) {
+            continue;
+        }
+        f.read_to_end(&mut content)?;
+        if let Some(recipe) = Recipe::parse(&content) {
+            ids.extend(recipe.chunks.into_iter().map(|c| c.id));
+        }
+    }
+    Ok(ids)
+}
+
+/// LIST_CMD print `<stored size> <path>` of every chunk in the drive
+pub const LIST_CMD: &str = "find /chunks -type f -exec wc -c {} + 2>/dev/null; true";
+
+/// parse_listing map chunk id to its stored size from the output of `LIST_CMD`
+pub fn parse_listing(out: &str) -> HashMap<String, u64> {
+    let mut chunks = HashMap::new();
+    for line in out.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 2 || !fields[1].starts_with(CHUNKS_DIR) {
+            continue;
+        }
+        if let (Ok(size), Some(id)) = (fields[0].parse(), fields[1].rsplit('/').next()) {
+            chunks.insert(id.to_owned(), size);
+        }
+    }
+    chunks
+}
+
+/// RECIPES_CMD print every recipe of items in the drive and in its trash.
+/// It fails rather than print too few, so no chunk in use is taken for garbage
+pub const RECIPES_CMD: &str = "mkdir -p /data /trash && find /data /trash -type f -exec sh -c \
+     'for f; do if head -c 10 \"$f\" | grep -qx IDCHUNKS1; then cat \"$f\"; fi; done' sh {} +";
+
+/// unreferenced return ids of `chunks` which no recipe in `recipes`, the output of
+/// `RECIPES_CMD`, refers to. Any line which looks like a chunk of a recipe keeps its chunk
+pub fn unreferenced(recipes: &str, chunks: &HashMap<String, u64>) -> Vec<String> {
+    let used: HashSet<&str> = recipes
+        .lines()
+        .filter_map(|line| line.split(' ').next())
+        .collect();
+    let mut ids: Vec<String> = chunks
+        .keys()
+        .filter(|id| !used.contains(id.as_str()))
+        .cloned()
+        .collect();
+    ids.sort();
+    ids
+}
+
+/// ChunkStore stage new chunks of files bigger than `threshold` in `dir`,
+/// chunks in `existing` (id to stored size) are already in the drive and are not staged again
+pub struct ChunkStore {
+    pub threshold: u64,
+    dir: PathBuf,
+    existing: HashMap<String, u64>,
+}
+
+impl ChunkStore {
+    pub fn new(threshold: u64, dir: &Path, existing: HashMap<String, u64>) -> Self {
+        ChunkStore {
+            threshold,
+            dir: dir.to_path_buf(),
+            existing,
+        }
+    }
+
+    /// split `r` into chunks, stage new ones and return the recipe and stored size of new chunks
+    pub fn split<R: Read>(&mut self, r: R, codec: &Codec) -> io::Result<(Recipe, u64)> {
+        let mut recipe = Recipe {
+            size: 0,
+            chunks: vec![],
+        };
+        let mut new_stored = 0;
+        for chunk in fastcdc::v2020::StreamCDC::new(r, MIN_SIZE, AVG_SIZE, MAX_SIZE) {
+            let chunk = chunk.map_err(|e| io::Error::other(e.to_string()))?;
+            let id = codec.chunk_id(&chunk.data);
+            let stored = match self.existing.get(&id) {
+                Some(stored) => *stored,
+                None => {
+                    let path = self.dir.join(chunk_path(&id).trim_start_matches('/'));
+                    std::fs::create_dir_all(path.parent().unwrap())?;
+                    let mut encoded = vec![];
+                    codec.encode(&chunk.data[..], &mut encoded)?;
+                    std::fs::write(&path, &encoded)?;
+                    let stored = encoded.len() as u64;
+                    self.existing.insert(id.clone(), stored);
+                    new_stored += stored;
+                    stored
+                }
+            };
+            recipe.size += chunk.length as u64;
+            recipe.chunks.push(ChunkRef {
+                id,
+                len: chunk.length as u64,
+                stored,
+            });
+        }
+        Ok((recipe, new_stored))
+    }
+}
+
+/// assemble read stored chunks of `recipe` one after another from `chunks`,
+/// decode them and write the original content to `w`
+pub fn assemble<R: Read, W: Write>(
+    recipe: &Recipe,
+    mut chunks: R,
+    codec: &Codec,
+    mut w: W,
+) -> io::Result<()> {
+    for c in &recipe.chunks {
+        let mut stored = vec![0u8; c.stored as usize];
+        chunks.read_exact(&mut stored)?;
+        let mut original = vec![];
+        codec
+            .decode(io::Cursor::new(stored))?
+            .read_to_end(&mut original)?;
+        if original.len() as u64 != c.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk {} is corrupt", c.id),
+            ));
+        }
+        w.write_all(&original)?;
+    }
+    w.flush()
+}
+
+/// content_id is the chunk id of `data` when no key is configured
+pub fn content_id(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recipe_roundtrip() {
+        let recipe = Recipe {
+            size: 30,
+            chunks: vec![
+                ChunkRef {
+                    id: "ab01".to_owned(),
+                    len: 10,
+                    stored: 12,
+                },
+                ChunkRef {
+                    id: "cd02".to_owned(),
+                    len: 20,
+                    stored: 8,
+                },
+            ],
+        };
+        assert_eq!(Recipe::parse(recipe.format().as_bytes()), Some(recipe));
+        assert_eq!(Recipe::parse(b"plain content"), None);
+    }
+
+    #[test]
+    fn split_and_assemble() {
+        let dir = tempfile::tempdir().unwrap();
+        let codec = Codec::new(Some(crate::codec::Compression::Zstd), None);
+        let mut store = ChunkStore::new(0, dir.path(), HashMap::new());
+        // pseudo random, so chunk boundaries are content defined
+        let mut x: u32 = 1;
+        let data: Vec<u8> = (0..3 * MAX_SIZE)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect();
+        let (recipe, new_stored) = store.split(&data[..], &codec).unwrap();
+        assert!(recipe.chunks.len() > 1);
+        assert_eq!(recipe.size, data.len() as u64);
+
+        // the same content again stages nothing
+        let (again, stored_again) = store.split(&data[..], &codec).unwrap();
+        assert_eq!(again, recipe);
+        assert_eq!(stored_again, 0);
+
+        let mut stream = vec![];
+        for p in recipe.paths() {
+            let staged = dir.path().join(p.trim_start_matches('/'));
+            stream.extend(std::fs::read(staged).unwrap());
+        }
+        assert_eq!(stream.len() as u64, new_stored);
+        let mut assembled = vec![];
+        assemble(&recipe, &stream[..], &codec, &mut assembled).unwrap();
+        assert_eq!(assembled, data);
+    }
+
+    #[test]
+    fn parse_chunk_listing() {
+        let out = "12 /chunks/ab/ab01\n7 /chunks/cd/cd02\n19 total\n";
+        let chunks = parse_listing(out);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks["ab01"], 12);
+    }
+
+    #[test]
+    fn unreferenced_chunks() {
+        let chunks = parse_listing("1 /chunks/ab/ab01\n2 /chunks/cd/cd02\n3 /chunks/ef/ef03\n");
+        let recipe = |ids: &[&str]| Recipe {
+            size: 2,
+            chunks: ids
+                .iter()
+                .map(|id| ChunkRef {
+                    id: id.to_string(),
+                    len: 1,
+                    stored: 1,
+                })
+                .collect(),
+        };
+        let recipes = recipe(&["ab01"]).format() + &recipe(&["ab01", "ef03"]).format();
+        assert_eq!(unreferenced(&recipes, &chunks), vec!["cd02"]);
+        assert_eq!(unreferenced("", &chunks), vec!["ab01", "cd02", "ef03"]);
+    }
+
+    #[test]
+    fn recipe_ids_of_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let recipe = Recipe {
+            size: 1,
+            chunks: vec![ChunkRef {
+                id: "ab01".to_owned(),
+                len: 1,
+                stored: 1,
+            }],
+        };
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/big"), recipe.format()).unwrap();
+        std::fs::write(dir.path().join("small"), "IDCHUNKS").unwrap();
+        assert_eq!(recipe_ids(dir.path()).unwrap(), vec!["ab01"]);
+        assert_eq!(recipe_ids(&dir.path().join("small")).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,292 @@
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hex::FromHex;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// encrypted items start with MAGIC, then a 7 bytes stream nonce, then segments
+const MAGIC: &[u8] = b"IDENC\x01";
+const NONCE_LEN: usize = 7;
+/// plaintext size of a segment, each encrypted segment is 16 bytes longer
+const SEGMENT: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+/// longest file name, an encrypted name is 2 * (28 + len) hex chars
+const NAME_MAX: usize = 255;
+
+#[derive(Debug)]
+pub enum Error {
+    BadKey(String),
+    IOError(io::Error),
+}
+
+/// Cipher encrypt item contents with AES-256-GCM (STREAM construction),
+/// and optionally entry and item names
+#[derive(Clone)]
+pub struct Cipher {
+    key: Key<Aes256Gcm>,
+    encrypt_names: bool,
+}
+
+impl Cipher {
+    /// new create Cipher from a 32 bytes key
+    pub fn new(key: &[u8], encrypt_names: bool) -> Result<Self, Error> {
+        if key.len() != 32 {
+            return Err(Error::BadKey(format!(
+                "key must be 32 bytes, got {}",
+                key.len()
+            )));
+        }
+        Ok(Cipher {
+            key: *Key::<Aes256Gcm>::from_slice(key),
+            encrypt_names,
+        })
+    }
+
+    /// from_hex create Cipher from a key of 64 hex chars
+    pub fn from_hex(key: &str, encrypt_names: bool) -> Result<Self, Error> {
+        let key = Vec::from_hex(key.trim()).map_err(|e| Error::BadKey(e.to_string()))?;
+        Cipher::new(&key, encrypt_names)
+    }
+
+    /// from_keyfile read key from `path`, which holds 32 raw bytes or 64 hex chars
+    pub fn from_keyfile<P: AsRef<Path>>(path: P, encrypt_names: bool) -> Result<Self, Error> {
+        let content = std::fs::read(path).map_err(Error::IOError)?;
+        match String::from_utf8(content.clone()) {
+            Ok(ref s) if s.trim().len() == 64 => Cipher::from_hex(s, encrypt_names),
+            _ => Cipher::new(&content, encrypt_names),
+        }
+    }
+
+    /// encrypt read plaintext from `r` and write ciphertext to `w`
+    pub fn encrypt<R: Read, W: Write>(&self, mut r: R, mut w: W) -> io::Result<()> {
+        let mut nonce = [0u8; NONCE_LEN];
+        aes_gcm::aead::rand_core::RngCore::fill_bytes(&mut OsRng, &mut nonce);
+        let mut encryptor =
+            EncryptorBE32::from_aead(Aes256Gcm::new(&self.key), nonce.as_ref().into());
+        w.write_all(MAGIC)?;
+        w.write_all(&nonce)?;
+        let mut buf = vec![0u8; SEGMENT];
+        let mut n = read_full(&mut r, &mut buf)?;
+        loop {
+            let mut next = vec![0u8; SEGMENT];
+            let m = if n == SEGMENT {
+                read_full(&mut r, &mut next)?
+            } else {
+                0
+            };
+            if m == 0 {
+                let segment = encryptor.encrypt_last(&buf[..n]).map_err(aead_error)?;
+                w.write_all(&segment)?;
+                return w.flush();
+            }
+            let segment = encryptor.encrypt_next(&buf[..n]).map_err(aead_error)?;
+            w.write_all(&segment)?;
+            buf = next;
+            n = m;
+        }
+    }
+
+    /// decrypt is the reverse of `encrypt`
+    pub fn decrypt<R: Read, W: Write>(&self, r: R, mut w: W) -> io::Result<()> {
+        io::copy(&mut self.decrypt_reader(r)?, &mut w)?;
+        w.flush()
+    }
+
+    /// decrypt_reader return a reader of plaintext of encrypted `r`
+    pub fn decrypt_reader<R: Read>(&self, mut r: R) -> io::Result<DecryptReader<R>> {
+        let mut header = [0u8; 6 + NONCE_LEN];
+        r.read_exact(&mut header)?;
+        if !is_encrypted(&header) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an encrypted item",
+            ));
+        }
+        let nonce = &header[MAGIC.len()..];
+        let decryptor = DecryptorBE32::from_aead(Aes256Gcm::new(&self.key), nonce.into());
+        let mut next = vec![0u8; SEGMENT + TAG_LEN];
+        let n = read_full(&mut r, &mut next)?;
+        next.truncate(n);
+        Ok(DecryptReader {
+            inner: r,
+            decryptor: Some(decryptor),
+            next,
+            plain: vec![],
+            pos: 0,
+        })
+    }
+
+    /// store_name is the name saved in the drive for entry or item `name`.
+    /// Encrypted names are deterministic, so an item is found by its name.
+    /// It fails if the encrypted name is too long for a file name
+    pub fn store_name(&self, name: &str) -> io::Result<String> {
+        if !self.encrypt_names {
+            return Ok(name.to_owned());
+        }
+        if 2 * (12 + name.len() + TAG_LEN) > NAME_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("name too long to encrypt: {}", name),
+            ));
+        }
+        let mut hasher = Sha256::new();
+        hasher.input(self.key);
+        hasher.input(name.as_bytes());
+        let digest = hasher.result();
+        let nonce = Nonce::from_slice(&digest[..12]);
+        let ciphertext = Aes256Gcm::new(&self.key)
+            .encrypt(nonce, name.as_bytes())
+            .expect("encrypt name");
+        Ok(hex::encode([&digest[..12], &ciphertext[..]].concat()))
+    }
+
+    /// keyed_hash is the hex sha256 of `data` keyed with the key,
+    /// so ids of encrypted content don't reveal the content
+    pub fn keyed_hash(&self, data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(self.key);
+        hasher.input(data);
+        hex::encode(hasher.result())
+    }
+
+    /// display_name is the reverse of `store_name`,
+    /// names which are not encrypted with this key are returned as is
+    pub fn display_name(&self, stored: &str) -> String {
+        if !self.encrypt_names {
+            return stored.to_owned();
+        }
+        Vec::from_hex(stored)
+            .ok()
+            .filter(|bytes| bytes.len() > 12)
+            .and_then(|bytes| {
+                Aes256Gcm::new(&self.key)
+                    .decrypt(Nonce::from_slice(&bytes[..12]), &bytes[12..])
+                    .ok()
+            })
+            .and_then(|name| String::from_utf8(name).ok())
+            .unwrap_or_else(|| stored.to_owned())
+    }
+}
+
+/// is_encrypted tell if `header`, the first bytes of an item, is written by `Cipher::encrypt`
+pub fn is_encrypted(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+/// DecryptReader read plaintext segment by segment, see `Cipher::decrypt_reader`
+pub struct DecryptReader<R> {
+    inner: R,
+    /// None after the last segment is decrypted
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    /// next encrypted segment, read ahead to know which segment is the last one
+    next: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn decrypt_segment(&mut self) -> io::Result<()> {
+        let mut following = vec![0u8; SEGMENT + TAG_LEN];
+        let m = if self.next.len() == SEGMENT + TAG_LEN {
+            read_full(&mut self.inner, &mut following)?
+        } else {
+            0
+        };
+        following.truncate(m);
+        let segment = std::mem::replace(&mut self.next, following);
+        self.plain = if m == 0 {
+            let decryptor = self.decryptor.take().unwrap();
+            decryptor.decrypt_last(&segment[..]).map_err(aead_error)?
+        } else {
+            let decryptor = self.decryptor.as_mut().unwrap();
+            decryptor.decrypt_next(&segment[..]).map_err(aead_error)?
+        };
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.decrypt_segment()?;
+        }
+        let n = std::cmp::min(buf.len(), self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+fn aead_error(_: aes_gcm::aead::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "decrypt fail, wrong key or corrupt item",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(len: usize) {
+        let cipher = Cipher::new(&[7u8; 32], false).unwrap();
+        let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let mut encrypted = vec![];
+        cipher.encrypt(&plain[..], &mut encrypted).unwrap();
+        let mut decrypted = vec![];
+        cipher.decrypt(&encrypted[..], &mut decrypted).unwrap();
+        assert_eq!(decrypted, plain);
+    }
+
+    #[test]
+    fn encrypt_roundtrip() {
+        roundtrip(0);
+        roundtrip(10);
+        roundtrip(SEGMENT);
+        roundtrip(SEGMENT * 2 + 1);
+    }
+
+    #[test]
+    fn decrypt_with_wrong_key() {
+        let mut encrypted = vec![];
+        Cipher::new(&[7u8; 32], false)
+            .unwrap()
+            .encrypt(&b"secret"[..], &mut encrypted)
+            .unwrap();
+        let other = Cipher::new(&[8u8; 32], false).unwrap();
+        assert!(other.decrypt(&encrypted[..], &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn names_are_deterministic() {
+        let cipher = Cipher::new(&[7u8; 32], true).unwrap();
+        let stored = cipher.store_name("report.csv").unwrap();
+        assert_eq!(stored, cipher.store_name("report.csv").unwrap());
+        assert_ne!(stored, "report.csv");
+        assert_eq!(cipher.display_name(&stored), "report.csv");
+        assert_eq!(cipher.display_name("plain"), "plain");
+        let longest = "a".repeat(99);
+        assert_eq!(cipher.store_name(&longest).unwrap().len(), 254);
+        assert!(cipher.store_name(&format!("{}a", longest)).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+use crate::chunk::{assemble, is_recipe, ChunkStore, Recipe};
+use crate::cipher::{is_encrypted, Cipher};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// compressed items start with MAGIC and one byte of `Compression::id`
+const MAGIC: &[u8] = b"IDZ\x01";
+/// uncompressed items which start like an encoded one are stored after RAW
+const RAW: &[u8] = b"IDRAW\x01";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::Gzip => 1,
+            Compression::Zstd => 2,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression: {}, use gzip or zstd", s)),
+        }
+    }
+}
+
+/// Sizes of an item, in bytes
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Sizes {
+    pub original: u64,
+    /// size inside the drive, after compression and encryption
+    pub stored: u64,
+}
+
+/// Codec transform files on their way into the drive: compress, then encrypt.
+/// Decoding looks at the header of each file, so items stored with other settings
+/// (or before compression or encryption was enabled) still decode
+pub struct Codec<'a> {
+    compression: Option<Compression>,
+    cipher: Option<&'a Cipher>,
+}
+
+impl<'a> Codec<'a> {
+    pub fn new(compression: Option<Compression>, cipher: Option<&'a Cipher>) -> Self {
+        Codec {
+            compression,
+            cipher,
+        }
+    }
+
+    /// is_identity is true if encode doesn't change anything,
+    /// but escape content which starts like encoded content
+    pub fn is_identity(&self) -> bool {
+        self.compression.is_none() && self.cipher.is_none()
+    }
+
+    /// encode read original content from `r` and write stored content to `w`
+    pub fn encode<R: Read, W: Write>(&self, mut r: R, mut w: W) -> io::Result<()> {
+        let mut r: Box<dyn Read> = match self.compression {
+            None => {
+                let mut header = vec![];
+                (&mut r).take(16).read_to_end(&mut header)?;
+                let escape = if is_encoded(&header) { RAW } else { &[] };
+                Box::new(io::Cursor::new([escape, &header[..]].concat()).chain(r))
+            }
+            Some(c) => {
+                let header = io::Cursor::new([MAGIC, &[c.id()]].concat());
+                match c {
+                    Compression::Gzip => Box::new(header.chain(flate2::read::GzEncoder::new(
+                        r,
+                        flate2::Compression::default(),
+                    ))),
+                    Compression::Zstd => {
+                        Box::new(header.chain(zstd::stream::read::Encoder::new(r, 0)?))
+                    }
+                }
+            }
+        };
+        match self.cipher {
+            None => {
+                io::copy(&mut r, &mut w)?;
+                w.flush()
+            }
+            Some(cipher) => cipher.encrypt(r, w),
+        }
+    }
+
+    /// decode return a reader of original content of stored `r`
+    pub fn decode<R: Read + 'static>(&self, r: R) -> io::Result<Box<dyn Read>> {
+        let mut r = BufReader::new(r);
+        let r: Box<dyn Read> = if is_encrypted(r.fill_buf()?) {
+            match self.cipher {
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "item is encrypted, but no key is configured",
+                    ))
+                }
+                Some(cipher) => Box::new(cipher.decrypt_reader(r)?),
+            }
+        } else {
+            Box::new(r)
+        };
+        let mut r = BufReader::new(r);
+        if r.fill_buf()?.starts_with(RAW) {
+            r.consume(RAW.len());
+            return Ok(Box::new(r));
+        }
+        let compression = {
+            let header = r.fill_buf()?;
+            if header.len() > MAGIC.len() && header.starts_with(MAGIC) {
+                Some(header[MAGIC.len()])
+            } else {
+                None
+            }
+        };
+        match compression {
+            None => Ok(Box::new(r)),
+            Some(id) => {
+                r.consume(MAGIC.len() + 1);
+                match id {
+                    1 => Ok(Box::new(flate2::read::GzDecoder::new(r))),
+                    2 => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(r)?)),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown compression id: {}", id),
+                    )),
+                }
+            }
+        }
+    }
+
+    /// store_name is the name of entry, item or file `name` inside the drive
+    pub fn store_name(&self, name: &str) -> io::Result<String> {
+        match self.cipher {
+            Some(cipher) => cipher.store_name(name),
+            None => Ok(name.to_owned()),
+        }
+    }
+
+    pub fn display_name(&self, stored: &str) -> String {
+        match self.cipher {
+            Some(cipher) => cipher.display_name(stored),
+            None => stored.to_owned(),
+        }
+    }
+
+    /// chunk_id is the id of chunk `data`, keyed when a cipher is configured
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        match self.cipher {
+            Some(cipher) => cipher.keyed_hash(data),
+            None => crate::chunk::content_id(data),
+        }
+    }
+
+    /// encode_tree encode file or directory `src` to `dst`.
+    /// Files bigger than the threshold of `chunks` are split into chunks staged by `chunks`,
+    /// and `dst` gets their recipe; stored size counts only chunks which are new
+    pub fn encode_tree(
+        &self,
+        src: &Path,
+        dst: &Path,
+        chunks: &mut Option<ChunkStore>,
+    ) -> io::Result<Sizes> {
+        if src.is_dir() {
+            std::fs::create_dir_all(dst)?;
+            let mut sizes = Sizes::default();
+            for entry in std::fs::read_dir(src)? {
+                let entry = entry?;
+                let name = self.store_name(&entry.file_name().to_string_lossy())?;
+                let s = self.encode_tree(&entry.path(), &dst.join(name), chunks)?;
+                sizes.original += s.original;
+                sizes.stored += s.stored;
+            }
+            return Ok(sizes);
+        }
+        let original = src.metadata()?.len();
+        if let Some(store) = chunks.as_mut().filter(|s| original > s.threshold) {
+            let (recipe, new_stored) = store.split(BufReader::new(File::open(src)?), self)?;
+            let content = recipe.format();
+            std::fs::write(dst, &content)?;
+            return Ok(Sizes {
+                original,
+                stored: content.len() as u64 + new_stored,
+            });
+        }
+        self.encode(File::open(src)?, BufWriter::new(File::create(dst)?))?;
+        Ok(Sizes {
+            original,
+            stored: dst.metadata()?.len(),
+        })
+    }
+
+    /// decode_in_place decode files under `path` which are stored encoded,
+    /// and give them back their names. `path` itself is not renamed.
+    /// `fetch` return the stored chunks of a recipe, one after another
+    pub fn decode_in_place(
+        &self,
+        path: &Path,
+        fetch: &dyn Fn(&Recipe) -> io::Result<Box<dyn Read>>,
+    ) -> io::Result<()> {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?;
+                self.decode_in_place(&entry.path(), fetch)?;
+                let stored = entry.file_name().to_string_lossy().to_string();
+                let name = self.display_name(&stored);
+                if name != stored {
+                    std::fs::rename(entry.path(), path.join(name))?;
+                }
+            }
+            return Ok(());
+        }
+        let mut header = vec![];
+        File::open(path)?.take(16).read_to_end(&mut header)?;
+        let recipe = if is_recipe(&header) {
+            Recipe::parse(&std::fs::read(path)?)
+        } else {
+            None
+        };
+        if recipe.is_none() && !is_encoded(&header) {
+            return Ok(());
+        }
+        let name = path.file_name().unwrap().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{}.decoding", name));
+        if let Some(recipe) = recipe {
+            let w = BufWriter::new(File::create(&tmp)?);
+            assemble(&recipe, fetch(&recipe)?, self, w)?;
+            return std::fs::rename(tmp, path);
+        }
+        {
+            let mut r = self.decode(File::open(path)?)?;
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            io::copy(&mut r, &mut w)?;
+            w.flush()?;
+        }
+        std::fs::rename(tmp, path)
+    }
+}
+
+/// is_encoded tell if `header`, the first bytes of a stored file, is of encoded content
+fn is_encoded(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+        || header.starts_with(RAW)
+        || is_encrypted(header)
+        || is_recipe(header)
+}
+
+/// tree_size is the total size of files under `path`
+pub fn tree_size(path: &Path) -> io::Result<u64> {
+    if path.is_dir() {
+        let mut size = 0;
+        for entry in std::fs::read_dir(path)? {
+            size += tree_size(&entry?.path())?;
+        }
+        return Ok(size);
+    }
+    Ok(path.metadata()?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(codec: &Codec, plain: &[u8]) -> usize {
+        let mut stored = vec![];
+        codec.encode(plain, &mut stored).unwrap();
+        let mut decoded = vec![];
+        codec
+            .decode(io::Cursor::new(stored.clone()))
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, plain);
+        stored.len()
+    }
+
+    #[test]
+    fn compress_then_encrypt() {
+        let plain = "imagedrive ".repeat(10000).into_bytes();
+        let cipher = Cipher::new(&[1u8; 32], false).unwrap();
+        for compression in &[None, Some(Compression::Gzip), Some(Compression::Zstd)] {
+            let stored = roundtrip(&Codec::new(*compression, None), &plain);
+            if compression.is_some() {
+                assert!(stored < plain.len() / 10);
+            }
+            roundtrip(&Codec::new(*compression, Some(&cipher)), &plain);
+        }
+    }
+
+    #[test]
+    fn raw_content_like_encoded_content() {
+        let cipher = Cipher::new(&[1u8; 32], false).unwrap();
+        let codecs = [
+            Codec::new(None, None),
+            Codec::new(None, Some(&cipher)),
+            Codec::new(Some(Compression::Gzip), None),
+        ];
+        for magic in &[MAGIC, RAW, b"IDENC\x01", b"IDCHUNKS1\n"] {
+            for plain in &[magic.to_vec(), [magic, &b"\x02 rest"[..]].concat()] {
+                for codec in &codecs {
+                    roundtrip(codec, plain);
+                }
+            }
+        }
+        assert_eq!(roundtrip(&codecs[0], b"plain"), 5);
+    }
+
+    #[test]
+    fn decode_items_stored_before_compression() {
+        let mut decoded = vec![];
+        Codec::new(Some(Compression::Zstd), None)
+            .decode(io::Cursor::new(b"plain".to_vec()))
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, b"plain");
+    }
+}
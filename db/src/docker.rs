@@ -1,67 +1,921 @@
-use crate::utils::{get_or_run, run};
+use crate::utils::{
+    free_name, get_or_run, matches, orphans, quote, repository, run, running_work_container, LABEL,
+};
 use crate::*;
-use hex;
 use std::fmt;
 use std::path::Path;
 #[derive(Debug)]
 pub enum Error {
     NotExistItem(String),
     NotFoundEntry(String),
+    NotFoundItem(String),
     DockerError(dockerclient::Error),
     ExecError(String),
     HostItemError(hostitem::Error),
     ContainerItemError(containeritem::Error),
+    /// a container with this drive's label runs another image
+    ForeignContainer(String),
+    IOError(std::io::Error),
+    NotFoundRevision(u32),
+    /// image layer of the revision is not on this host, e.g. the drive is pulled
+    RevisionUnavailable(u32),
+    /// snapshot name is not a valid docker tag, or is the tag of the drive itself
+    BadSnapshotName(String),
+    /// item name is empty, `.`, `..` or has a `/`
+    BadItemName(String),
+    /// localDB has this many changes not synced
+    UnsyncedChanges(usize),
+    /// item to restore from the trash exists in localDB
+    ExistedItem(String),
+    /// no local index of the image is cached, it is cached by any command run with docker up
+    NotCached(String),
+    MetaCacheError(String),
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "imagedrive error")
+        match self {
+            Error::NotExistItem(p) => write!(f, "no such file: {}", p),
+            Error::NotFoundEntry(e) => write!(f, "entry not found: {}", e),
+            Error::NotFoundItem(i) => write!(f, "item not found: {}", i),
+            Error::DockerError(e) => write!(f, "docker: {}", e),
+            Error::ExecError(e) => write!(f, "exec in container: {}", e.trim()),
+            Error::HostItemError(e) => write!(f, "host item: {}", e),
+            Error::ContainerItemError(e) => write!(f, "container item: {}", e),
+            Error::ForeignContainer(c) => write!(f, "container {} runs another image", c),
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::NotFoundRevision(r) => write!(f, "revision not found: {}", r),
+            Error::RevisionUnavailable(r) => write!(f, "revision {} is not on this host", r),
+            Error::BadSnapshotName(n) => write!(f, "bad snapshot name: {}", n),
+            Error::BadItemName(n) => write!(f, "bad item name: {:?}", n),
+            Error::UnsyncedChanges(n) => write!(f, "{} changes are not synced", n),
+            Error::ExistedItem(i) => write!(f, "item exists: {}", i),
+            Error::NotCached(i) => write!(f, "no cached index of {}", i),
+            Error::MetaCacheError(e) => write!(f, "index cache: {}", e),
+        }
     }
 }
 
+impl std::error::Error for Error {}
+
+/// DoctorReport is the result of `ImageDrive::doctor`, values are container ids
+#[derive(Debug)]
+pub struct DoctorReport {
+    /// scratch containers left behind by killed imagedrive processes
+    pub orphans: Vec<String>,
+    /// orphans removed, when doctor run with prune
+    pub removed: Vec<String>,
+    /// containers of this drive created before containers were labeled,
+    /// they may hold unsynced data so they are never pruned
+    pub unlabeled: Vec<String>,
+}
+
+/// FsckReport is the result of `ImageDrive::fsck`, values are `<entry>/<item>`
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// number of items re-hashed
+    pub checked: usize,
+    /// items without checksum
+    pub missing: Vec<String>,
+    /// checksum files without item, e.g. left behind by delete
+    pub orphaned: Vec<String>,
+    /// items whose content doesn't match their checksum, or can't be read back
+    pub corrupt: Vec<String>,
+    /// missing checksums rebuilt and orphaned ones removed, when fsck run with repair
+    pub repaired: Vec<String>,
+}
+
 pub struct ImageDrive {
     image_name: String,
     dockercli: dockerclient::DockerClient,
+    cipher: Option<cipher::Cipher>,
+    compression: Option<codec::Compression>,
+    chunk_threshold: u64,
+    jobs: usize,
+    algorithm: hash::Algorithm,
+    hash_cache: Option<hashcache::HashCache>,
+    meta_cache: Option<metacache::MetaCache>,
 }
 
 impl ImageDrive {
     pub fn new(image_name: &str, server: &str, username: &str, password: &str) -> ImageDrive {
-        let dockercli = dockerclient::DockerClient::new_with_logininfo(
-            Some(server),
-            Some(username),
-            Some(password),
-        );
+        ImageDrive::with_client(
+            image_name,
+            dockerclient::DockerClient::new_with_logininfo(
+                Some(server),
+                Some(username),
+                Some(password),
+            ),
+        )
+    }
+
+    /// open drive `image_name` with the credentials docker keeps, e.g. from `docker login`
+    /// or a credential helper, so no password is handled here
+    pub fn open(image_name: &str) -> ImageDrive {
+        ImageDrive::with_client(image_name, dockerclient::DockerClient::new())
+    }
+
+    fn with_client(image_name: &str, dockercli: dockerclient::DockerClient) -> ImageDrive {
         ImageDrive {
             image_name: image_name.to_string(),
-            dockercli: dockercli,
+            dockercli,
+            cipher: None,
+            compression: None,
+            chunk_threshold: chunk::DEFAULT_THRESHOLD,
+            jobs: parallel::default_jobs(),
+            algorithm: hash::Algorithm::default(),
+            hash_cache: None,
+            meta_cache: None,
+        }
+    }
+    /// with_progress report transfers of the drive to `progress`
+    pub fn with_progress(
+        mut self,
+        progress: std::sync::Arc<dyn dockerclient::Progress>,
+    ) -> ImageDrive {
+        self.dockercli = self.dockercli.with_progress(progress);
+        self
+    }
+    /// with_chunk_threshold split files bigger than `threshold` bytes into chunks
+    pub fn with_chunk_threshold(mut self, threshold: u64) -> ImageDrive {
+        self.chunk_threshold = threshold;
+        self
+    }
+    /// with_jobs hash and read checksums on up to `jobs` threads, the number of cpus by default
+    pub fn with_jobs(mut self, jobs: usize) -> ImageDrive {
+        self.jobs = jobs.max(1);
+        self
+    }
+    /// with_algorithm hash items added later with `algorithm`. Items are still compared
+    /// with checksums of other algorithms, so changing it keeps dedup and fsck correct
+    pub fn with_algorithm(mut self, algorithm: hash::Algorithm) -> ImageDrive {
+        self.algorithm = algorithm;
+        self
+    }
+    /// with_hash_cache reuse checksums of unchanged host items, remembered in file `path`
+    pub fn with_hash_cache(mut self, path: &Path) -> ImageDrive {
+        self.hash_cache = Some(hashcache::HashCache::open(path));
+        self
+    }
+    /// with_meta_cache keep a local copy of the index of the drive in `cache`,
+    /// to list it when docker is unreachable
+    pub fn with_meta_cache(mut self, cache: metacache::MetaCache) -> ImageDrive {
+        self.meta_cache = Some(cache);
+        self
+    }
+    /// with_compression compress items added later with `compression`
+    pub fn with_compression(mut self, compression: codec::Compression) -> ImageDrive {
+        self.compression = Some(compression);
+        self
+    }
+    fn codec(&self) -> codec::Codec<'_> {
+        codec::Codec::new(self.compression, self.cipher.as_ref())
+    }
+    /// with_cipher encrypt items added later with `cipher`, and decrypt them on export
+    pub fn with_cipher(mut self, cipher: cipher::Cipher) -> ImageDrive {
+        self.cipher = Some(cipher);
+        self
+    }
+    /// fetch_chunks return a reader of stored chunks of `recipe` in `container`
+    fn fetch_chunks(
+        &self,
+        container: &str,
+        recipe: &chunk::Recipe,
+    ) -> std::io::Result<Box<dyn std::io::Read>> {
+        // paths go through stdin, a big file has too many chunks for one command line
+        let paths = recipe.paths().join("\n");
+        self.dockercli
+            .exec_stream(container, "xargs cat", paths.into_bytes())
+            .map(|r| Box::new(r) as Box<dyn std::io::Read>)
+            .map_err(|e| std::io::Error::other(format!("{:?}", e)))
+    }
+    /// gc_chunks remove chunks which no item refers to, neither in the drive nor in its trash
+    fn gc_chunks(&self, container: &str) -> Result<(), Error> {
+        let chunks = chunk::parse_listing(&exec(&self.dockercli, container, chunk::LIST_CMD)?);
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        let recipes = exec(&self.dockercli, container, chunk::RECIPES_CMD)?;
+        let unused = chunk::unreferenced(&recipes, &chunks);
+        if unused.is_empty() {
+            return Ok(());
+        }
+        // paths go through stdin like in `fetch_chunks`
+        let paths: Vec<String> = unused.iter().map(|id| chunk::chunk_path(id)).collect();
+        let mut out = self
+            .dockercli
+            .exec_stream(container, "xargs rm -f", paths.join("\n").into_bytes())
+            .map_err(Error::DockerError)?;
+        std::io::copy(&mut out, &mut std::io::sink())
+            .map(|_| ())
+            .map_err(Error::IOError)
+    }
+    /// store_name is the name of entry or item `name` inside the drive
+    fn store_name(&self, name: &str) -> Result<String, Error> {
+        self.codec().store_name(name).map_err(Error::IOError)
+    }
+    fn display_name(&self, stored: &str) -> String {
+        self.codec().display_name(stored)
+    }
+    /// display_key is `display_name` of each part of `<entry>/<item>`
+    fn display_key(&self, key: &str) -> String {
+        key.split('/')
+            .map(|name| self.display_name(name))
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+    fn display_changes(&self, changes: Vec<Change>) -> Vec<Change> {
+        changes
+            .into_iter()
+            .map(|change| match change {
+                Change::Added(key) => Change::Added(self.display_key(&key)),
+                Change::Modified(key) => Change::Modified(self.display_key(&key)),
+                Change::Deleted(key) => Change::Deleted(self.display_key(&key)),
+            })
+            .collect()
+    }
+    /// index read items and their checksums in `container`
+    fn index(&self, container: &str) -> Result<index::Index, Error> {
+        exec(&self.dockercli, container, index::LIST_CMD).map(|out| index::parse_listing(&out))
+    }
+    /// committed_index read the index of the last commit from `container`.
+    /// Drives committed before the index existed are compared with a temporary container
+    /// once, the result is saved in `container` so next time is cheap.
+    fn committed_index(&self, container: &str) -> Result<index::Index, Error> {
+        // an empty index is of an empty drive, only a missing one is not computed yet
+        let cmd = format!(
+            "if [ -e {} ]; then echo committed; cat {}; fi",
+            index::COMMITTED_PATH,
+            index::COMMITTED_PATH
+        );
+        let out = exec(&self.dockercli, container, &cmd)?;
+        if let Some(committed) = out.strip_prefix("committed\n") {
+            return Ok(index::parse_committed(committed));
         }
+        let image_c = run(&self.dockercli, &self.image_name).map_err(Error::DockerError)?;
+        let committed = self.index(&image_c.id)?;
+        self.write_meta(
+            container,
+            &[(index::COMMITTED_PATH, index::format_committed(&committed))],
+        )?;
+        Ok(committed)
     }
-    // cat /checksum/data/*/* | sort
-    fn get_checksum_str(&self, container: &str) -> String {
-        let cmd = "cat /checksum/data/*/* | sort";
+    /// write_meta write `files` into `container`, paths are absolute and under /.imagedrive
+    fn write_meta(&self, container: &str, files: &[(&str, String)]) -> Result<(), Error> {
+        let tmp = tempfile::tempdir().map_err(Error::IOError)?;
+        for (path, content) in files {
+            let dst = tmp.path().join(Path::new(path).strip_prefix("/").unwrap());
+            std::fs::create_dir_all(dst.parent().unwrap()).map_err(Error::IOError)?;
+            std::fs::write(&dst, content).map_err(Error::IOError)?;
+        }
+        exec(&self.dockercli, container, "mkdir -p /.imagedrive")?;
         self.dockercli
-            .exec(container, cmd)
+            .copy_in(
+                container,
+                &tmp.path().join(".imagedrive/."),
+                Path::new("/.imagedrive"),
+            )
             .map_err(Error::DockerError)
-            .map(|(out, err)| {
-                (
-                    String::from_utf8_lossy(&out).to_owned().to_string(),
-                    String::from_utf8_lossy(&err).to_owned().to_string(),
-                )
+    }
+    fn read_file(&self, container: &str, path: &str) -> Result<String, Error> {
+        exec(
+            &self.dockercli,
+            container,
+            &format!("cat {} 2>/dev/null; true", path),
+        )
+    }
+    /// revision read revision `rev` and its changes, names are as stored
+    fn revision(&self, rev: u32) -> Result<(Revision, Vec<Change>), Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let revision = history::parse_log(&self.read_file(&c.id, history::LOG_PATH)?)
+            .into_iter()
+            .find(|r| r.rev == rev)
+            .ok_or(Error::NotFoundRevision(rev))?;
+        let changes = self.read_file(&c.id, &format!("{}/{}", history::HISTORY_DIR, rev))?;
+        Ok((revision, history::parse_changes(&changes)))
+    }
+    /// revision_images return image id of revision `rev` and of the revision before it
+    fn revision_images(&self, rev: u32) -> Result<(String, String), Error> {
+        let layers = self
+            .dockercli
+            .history(&self.image_name)
+            .map_err(Error::DockerError)?;
+        let pos = layers
+            .iter()
+            .position(|(_, comment)| history::parse_commit_message(comment) == Some(rev))
+            .ok_or(Error::NotFoundRevision(rev))?;
+        let id = &layers[pos].0;
+        let parent = layers
+            .get(pos + 1)
+            .map(|(id, _)| id.as_str())
+            .unwrap_or("<missing>");
+        if id == "<missing>" || parent == "<missing>" {
+            return Err(Error::RevisionUnavailable(rev));
+        }
+        Ok((id.clone(), parent.to_owned()))
+    }
+    /// restore_image replace items in `container` with items in `image`
+    fn restore_image(&self, container: &str, image: &str) -> Result<(), Error> {
+        let src = run(&self.dockercli, image).map_err(Error::DockerError)?;
+        let tmp = tempfile::tempdir().map_err(Error::IOError)?;
+        exec(&self.dockercli, &src.id, "mkdir -p /data /checksum /chunks")?;
+        for dir in &["data", "checksum", "chunks"] {
+            self.dockercli
+                .copy_out(&src.id, &Path::new("/").join(dir), &tmp.path().join(dir))
+                .map_err(Error::DockerError)?;
+        }
+        // chunks are shared by items, so they are merged instead of replaced
+        exec(
+            &self.dockercli,
+            container,
+            "rm -rf /data /checksum && mkdir -p /chunks",
+        )?;
+        self.dockercli
+            .copy_in(
+                container,
+                &tmp.path().join("chunks").join("."),
+                Path::new("/chunks"),
+            )
+            .map_err(Error::DockerError)?;
+        for dir in &["data", "checksum"] {
+            self.dockercli
+                .copy_in(container, &tmp.path().join(dir), &Path::new("/").join(dir))
+                .map_err(Error::DockerError)?;
+        }
+        self.gc_chunks(container)
+    }
+    /// snapshot_image is the image of snapshot `name`
+    fn snapshot_image(&self, name: &str) -> Result<String, Error> {
+        let (repo, tag) = repository(&self.image_name);
+        let valid = !name.is_empty()
+            && name.len() <= 128
+            && !name.starts_with('.')
+            && !name.starts_with('-')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+        if !valid || tag.is_none() || tag == Some(name) {
+            return Err(Error::BadSnapshotName(name.to_owned()));
+        }
+        Ok(format!("{}:{}", repo, name))
+    }
+    /// ensure_synced fail if localDB has unsynced changes, unless `force`
+    fn ensure_synced(&self, container: &str, force: bool) -> Result<(), Error> {
+        if force {
+            return Ok(());
+        }
+        let current = self.index(container)?;
+        let committed = self.committed_index(container)?;
+        let changes = index::diff(&committed, &current);
+        if !changes.is_empty() {
+            return Err(Error::UnsyncedChanges(changes.len()));
+        }
+        Ok(())
+    }
+    /// host_hashes hash host items at `paths` on up to `jobs` threads,
+    /// with each of `algorithms`
+    fn host_hashes<P: AsRef<Path> + Sync>(
+        &self,
+        paths: &[P],
+        algorithms: &[hash::Algorithm],
+    ) -> Vec<Result<Vec<String>, Error>> {
+        // a single item, e.g. a big directory, reads its files in parallel instead
+        let inner = if paths.len() == 1 { self.jobs } else { 1 };
+        parallel::map(paths, self.jobs, |path| {
+            hostitem::HostItem::new(path.as_ref(), None)
+                .and_then(|item| {
+                    item.with_jobs(inner)
+                        .with_algorithms(algorithms)
+                        .with_cache(self.hash_cache.as_ref())
+                        .checksums()
+                })
+                .map(|sums| self.keyed(sums))
+                .map_err(Error::HostItemError)
+        })
+    }
+    /// keyed is `sums` keyed with the cipher, if any, so the checksums stored in an
+    /// encrypted drive don't tell which content it holds
+    fn keyed(&self, sums: Vec<String>) -> Vec<String> {
+        let cipher = match self.cipher {
+            Some(ref cipher) => cipher,
+            None => return sums,
+        };
+        sums.into_iter()
+            .map(|sum| match hash::parse(&sum) {
+                Some((algorithm, digest)) => {
+                    format!("{}:{}", algorithm.name(), cipher.keyed_hash(&digest))
+                }
+                None => sum,
             })
-            .and_then(|(out, err)| {
-                if err != "" {
-                    return Err(Error::ExecError(err));
+            .collect()
+    }
+    /// plain_size is `size` unless the drive is encrypted, where it is not stored
+    fn plain_size(&self, size: u64) -> Option<u64> {
+        match self.cipher {
+            Some(_) => None,
+            None => Some(size),
+        }
+    }
+    /// algorithms to hash new items with to compare them with `stored` checksums,
+    /// the default one first
+    fn algorithms<'a, I: IntoIterator<Item = &'a String>>(
+        &self,
+        stored: I,
+    ) -> Vec<hash::Algorithm> {
+        let mut algorithms = vec![self.algorithm];
+        for sum in stored {
+            let algorithm = hash::algorithm_of(sum);
+            if !algorithms.contains(&algorithm) {
+                algorithms.push(algorithm);
+            }
+        }
+        algorithms
+    }
+    /// put_items is `add_many`, a single item is put under `rename` if given.
+    /// Items with the same content as another item in `entry` are only added if not `dedup`
+    fn put_items(
+        &self,
+        entry: &str,
+        itempaths: &[&Path],
+        rename: Option<&str>,
+        conflict: Conflict,
+        dedup: bool,
+    ) -> Result<AddResults<Error>, Error> {
+        let display_entry = entry;
+        let entry = self.store_name(entry)?;
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let dir = Path::new("/data").join(&entry);
+        // one read of the entry instead of a `cat` per existing item
+        let out = exec(
+            &self.dockercli,
+            &c.id,
+            &format!(
+                "mkdir -p {} && find {} -mindepth 1 -maxdepth 1 && \
+                 (grep -r . {} 2>/dev/null; true)",
+                quote(&dir),
+                quote(&dir),
+                quote(format!("/checksum{}", dir.display()))
+            ),
+        )?;
+        // stored name to checksums of items in the entry, and of items added so far
+        let mut existing: std::collections::BTreeMap<String, Vec<String>> =
+            index::parse_records(&out)
+                .into_iter()
+                .map(|(key, record)| {
+                    let name = key.rsplit('/').next().unwrap_or("").to_owned();
+                    (name, record.checksums())
+                })
+                .collect();
+        let algorithms = self.algorithms(existing.values().flatten());
+
+        // everything new is staged as it will be in the container: /data, /checksum and /chunks
+        let tmp = tempfile::tempdir().map_err(Error::IOError)?;
+        // not the tempdir itself, its mode would be copied to `/` of the container
+        let stage = &tmp.path().join("root");
+        let staged_dir = stage.join(dir.strip_prefix("/").unwrap());
+        let checksum_dir = stage.join("checksum").join(dir.strip_prefix("/").unwrap());
+        std::fs::create_dir_all(&staged_dir).map_err(Error::IOError)?;
+        std::fs::create_dir_all(&checksum_dir).map_err(Error::IOError)?;
+        // items which replace another one are staged aside, and swapped in only once copied
+        let put_dir = Path::new("/.imagedrive").join(format!("put-{}", trash::dir_name(now())));
+        let replace_stage = stage.join(put_dir.strip_prefix("/").unwrap());
+        let replace_dir = replace_stage.join(dir.strip_prefix("/").unwrap());
+        let replace_checksum_dir = replace_stage
+            .join("checksum")
+            .join(dir.strip_prefix("/").unwrap());
+        let mut replaced = 0;
+        let codec = self.codec();
+        let mut chunks = None;
+        let mut staged = 0;
+        let mut results = vec![];
+        let sums = self.host_hashes(itempaths, &algorithms);
+        for (itempath, sums) in itempaths.iter().zip(sums) {
+            let result = (|| {
+                if !itempath.exists() {
+                    return Err(Error::NotExistItem(format!("{}", itempath.display())));
+                }
+                let sums = sums?;
+                let same = existing
+                    .iter()
+                    .find(|(_, s)| index::same(s, &sums))
+                    .map(|(name, _)| name.clone());
+                if let Some(stored) = same.filter(|_| dedup) {
+                    return Ok(AddResult::ExistedItem(self.display_name(&stored)));
+                }
+                let mut name = match rename.filter(|_| itempaths.len() == 1) {
+                    Some(name) if !valid_item_name(name) => {
+                        return Err(Error::BadItemName(name.to_owned()));
+                    }
+                    Some(name) => name.to_owned(),
+                    None => itempath.file_name().unwrap().to_string_lossy().to_string(),
+                };
+                let mut result = AddResult::Succ;
+                let (mut data_dir, mut record_dir) = (&staged_dir, &checksum_dir);
+                if let Some(old) = existing.get(&self.store_name(&name)?).cloned() {
+                    match conflict {
+                        Conflict::NoClobber => {
+                            return Err(Error::ExistedItem(format!("{}/{}", display_entry, name)));
+                        }
+                        Conflict::Overwrite => {
+                            std::fs::create_dir_all(&replace_dir).map_err(Error::IOError)?;
+                            std::fs::create_dir_all(&replace_checksum_dir)
+                                .map_err(Error::IOError)?;
+                            data_dir = &replace_dir;
+                            record_dir = &replace_checksum_dir;
+                            result = AddResult::Replaced {
+                                item: name.clone(),
+                                old: old.first().cloned().unwrap_or_default(),
+                                new: sums[0].clone(),
+                            };
+                        }
+                        Conflict::Rename => {
+                            name = free_name(&name, |n| {
+                                self.store_name(n).is_ok_and(|n| existing.contains_key(&n))
+                            });
+                            result = AddResult::Renamed(name.clone());
+                        }
+                    }
+                }
+                let size = codec::tree_size(itempath).map_err(Error::IOError)?;
+                if size > self.chunk_threshold && chunks.is_none() {
+                    let out = exec(&self.dockercli, &c.id, chunk::LIST_CMD)?;
+                    chunks = Some(chunk::ChunkStore::new(
+                        self.chunk_threshold,
+                        stage,
+                        chunk::parse_listing(&out),
+                    ));
+                }
+                let stored = self.store_name(&name)?;
+                let sizes = codec
+                    .encode_tree(itempath, &data_dir.join(&stored), &mut chunks)
+                    .map_err(Error::IOError)?;
+                let record = index::Record {
+                    checksum: Some(sums[0].clone()),
+                    others: sums[1..].to_vec(),
+                    size: self.plain_size(sizes.original),
+                    stored: Some(sizes.stored),
+                    dir: false,
+                };
+                std::fs::write(record_dir.join(&stored), record.format())
+                    .map_err(Error::IOError)?;
+                if data_dir == &replace_dir {
+                    replaced += 1;
                 }
-                Ok(out.trim().to_owned())
+                existing.insert(stored, sums);
+                staged += 1;
+                Ok(result)
+            })();
+            results.push((itempath.to_path_buf(), result));
+        }
+        if staged > 0 {
+            // a single `docker cp`, so all items go in one tar stream
+            self.dockercli
+                .copy_in(&c.id, &stage.join("."), Path::new("/"))
+                .map_err(Error::DockerError)?;
+        }
+        if replaced > 0 {
+            // the old items go to the trash, so overwriting can be undone
+            let trash_dir = Path::new(trash::TRASH_DIR).join(trash::dir_name(now()));
+            let checksum = |p: &Path| Path::new("/checksum").join(p.strip_prefix("/").unwrap());
+            let new = put_dir.join(dir.strip_prefix("/").unwrap());
+            exec(
+                &self.dockercli,
+                &c.id,
+                &format!(
+                    "set -e; d={}; c={}; n={}; nc={}; td={}; tc={}; mkdir -p \"$c\" \"$td\" \"$tc\"; \
+                     for f in \"$n\"/* \"$n\"/.[!.]* \"$n\"/..?*; do [ -e \"$f\" ] || continue; \
+                     i=${{f##*/}}; if [ -e \"$d/$i\" ]; then mv \"$d/$i\" \"$td/\"; fi; \
+                     if [ -e \"$c/$i\" ]; then mv \"$c/$i\" \"$tc/\"; fi; \
+                     mv \"$f\" \"$d/$i\"; mv \"$nc/$i\" \"$c/$i\"; done; rm -rf {}",
+                    quote(&dir),
+                    quote(checksum(&dir)),
+                    quote(&new),
+                    quote(put_dir.join(checksum(&dir).strip_prefix("/").unwrap())),
+                    quote(trash_dir.join(dir.strip_prefix("/").unwrap())),
+                    quote(trash_dir.join(checksum(&dir).strip_prefix("/").unwrap())),
+                    quote(&put_dir)
+                ),
+            )?;
+        }
+        Ok(results)
+    }
+    /// entry_records map display names of items in `entry` to their checksum records,
+    /// `NotFoundEntry` if there is no such entry
+    fn entry_records(
+        &self,
+        entry: &str,
+    ) -> Result<std::collections::BTreeMap<String, index::Record>, Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let dir = Path::new("/data").join(self.store_name(entry)?);
+        let out = exec(
+            &self.dockercli,
+            &c.id,
+            &format!(
+                "if [ ! -d {} ]; then echo missing; exit; fi; \
+                 find {} -mindepth 1 -maxdepth 1 ! -type d && \
+                 (find {} -mindepth 1 -maxdepth 1 -type d | sed 's|$|/|') && \
+                 (grep -r . {} 2>/dev/null; true)",
+                quote(&dir),
+                quote(&dir),
+                quote(&dir),
+                quote(format!("/checksum{}", dir.display()))
+            ),
+        )?;
+        if out.trim() == "missing" {
+            return Err(Error::NotFoundEntry(entry.to_owned()));
+        }
+        Ok(index::parse_records(&out)
+            .into_iter()
+            .map(|(key, record)| {
+                let name = self.display_name(key.rsplit('/').next().unwrap_or(""));
+                (name, record)
             })
-            .expect("get checksum")
+            .collect())
+    }
+    /// entry_checksums map display names of items in `entry` to their checksums.
+    /// With `missing_empty`, an entry which doesn't exist yet is empty
+    fn entry_checksums(
+        &self,
+        entry: &str,
+        missing_empty: bool,
+    ) -> Result<std::collections::BTreeMap<String, Vec<String>>, Error> {
+        match self.entry_records(entry) {
+            Ok(records) => Ok(records
+                .into_iter()
+                .map(|(name, record)| (name, record.checksums()))
+                .collect()),
+            Err(Error::NotFoundEntry(_)) if missing_empty => Ok(std::collections::BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+    /// doctor find orphaned imagedrive containers, and remove them if `prune`
+    pub fn doctor(&self, prune: bool) -> Result<DoctorReport, Error> {
+        let orphans: Vec<String> = orphans(&self.dockercli)
+            .map_err(Error::DockerError)?
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        let mut removed = vec![];
+        if prune {
+            for id in &orphans {
+                self.dockercli.remove(id).map_err(Error::DockerError)?;
+                removed.push(id.clone());
+            }
+        }
+        let repo = self.image_name.split(':').next().unwrap_or("");
+        let unlabeled = self
+            .dockercli
+            .ps(true)
+            .map_err(Error::DockerError)?
+            .into_iter()
+            .filter(|c| !c.labels.contains_key(LABEL) && c.image.split(':').next() == Some(repo))
+            .map(|c| c.id)
+            .collect();
+        Ok(DoctorReport {
+            orphans,
+            removed,
+            unlabeled,
+        })
+    }
+    /// fsck re-hash every item and compare it with its checksum.
+    /// With `repair`, checksums of items without one are rebuilt and orphaned checksums
+    /// are removed; corrupt items are left alone, restore them with checkout or revert
+    pub fn fsck(&self, repair: bool) -> Result<FsckReport, Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let out = exec(&self.dockercli, &c.id, index::LIST_CMD)?;
+        let mut report = FsckReport::default();
+        for key in index::parse_orphans(&out) {
+            if repair {
+                let path = Path::new("/checksum/data").join(&key);
+                exec(&self.dockercli, &c.id, &format!("rm -f {}", quote(&path)))?;
+                report.repaired.push(self.display_key(&key));
+            }
+            report.orphaned.push(self.display_key(&key));
+        }
+        for (key, record) in index::parse_records(&out) {
+            report.checked += 1;
+            let src = Path::new("/data").join(&key);
+            let tmp = tempfile::tempdir().map_err(Error::IOError)?;
+            let stored = tmp.path().join("item");
+            // an item which can't be read back is corrupt, the others are still checked
+            let copied = self.dockercli.copy_out(&c.id, &src, &stored);
+            let stored_size = match copied.map(|_| codec::tree_size(&stored)) {
+                Ok(Ok(size)) => size,
+                _ => {
+                    report.corrupt.push(self.display_key(&key));
+                    continue;
+                }
+            };
+            let decoded = self
+                .codec()
+                .decode_in_place(&stored, &|recipe| self.fetch_chunks(&c.id, recipe));
+            if decoded.is_err() {
+                report.corrupt.push(self.display_key(&key));
+                continue;
+            }
+            let checksums = record.checksums();
+            let plain = match hostitem::HostItem::new(&stored, None).and_then(|item| {
+                item.with_jobs(self.jobs)
+                    .with_algorithms(&self.algorithms(&checksums))
+                    .checksums()
+            }) {
+                Ok(sums) => sums,
+                Err(_) => {
+                    report.corrupt.push(self.display_key(&key));
+                    continue;
+                }
+            };
+            let sums = self.keyed(plain.clone());
+            match record.checksum {
+                Some(_) if index::same(&checksums, &sums) => (),
+                // recorded before checksums of encrypted drives were keyed
+                Some(_) if index::same(&checksums, &plain) => (),
+                Some(_) => report.corrupt.push(self.display_key(&key)),
+                None => {
+                    if repair {
+                        let record = index::Record {
+                            checksum: Some(sums[0].clone()),
+                            others: vec![],
+                            size: self
+                                .plain_size(codec::tree_size(&stored).map_err(Error::IOError)?),
+                            stored: Some(stored_size),
+                            dir: false,
+                        };
+                        let path = Path::new("/checksum/data").join(&key);
+                        exec(
+                            &self.dockercli,
+                            &c.id,
+                            &format!(
+                                "mkdir -p {} && {}",
+                                quote(path.parent().unwrap()),
+                                record_cmd(&path, &record)
+                            ),
+                        )?;
+                        report.repaired.push(self.display_key(&key));
+                    }
+                    report.missing.push(self.display_key(&key));
+                }
+            }
+        }
+        Ok(report)
+    }
+    /// refresh_cache copy the index of the drive to the meta cache, if there is one.
+    /// Only a running work container is read, none is created or started for it
+    pub fn refresh_cache(&self) -> Result<(), Error> {
+        let cache = match self.meta_cache {
+            None => return Ok(()),
+            Some(ref cache) => cache,
+        };
+        let c = match running_work_container(&self.dockercli, &self.image_name)? {
+            None => return Ok(()),
+            Some(c) => c,
+        };
+        let out = exec(
+            &self.dockercli,
+            &c.id,
+            &format!(
+                "find /data -mindepth 1 -maxdepth 1 2>/dev/null; echo --; {}",
+                index::LIST_CMD
+            ),
+        )?;
+        let pos = out.find("--\n").unwrap_or(out.len());
+        let (entries, listing) = (&out[..pos], out.get(pos + 3..).unwrap_or(""));
+        let records = index::parse_records(listing);
+        let current = records
+            .iter()
+            .map(|(key, record)| (key.clone(), record.checksum.clone()))
+            .collect();
+        let committed = self.committed_index(&c.id)?;
+        let cached = metacache::Index {
+            entries: entries
+                .lines()
+                .filter_map(|l| l.trim().strip_prefix("/data/"))
+                .map(|e| e.to_owned())
+                .collect(),
+            changes: index::diff(&committed, &current),
+            records,
+        };
+        let stamp = metacache::Stamp {
+            digest: self
+                .dockercli
+                .image(&self.image_name)
+                .map(|image| image.id)
+                .unwrap_or_default(),
+            refreshed: now().as_secs(),
+        };
+        cache
+            .store(&self.image_name, &stamp, &cached)
+            .map_err(|e| Error::MetaCacheError(e.to_string()))
     }
-    // diff_container_with_image return true if different
-    fn diff_container_with_image(&self) -> bool {
-        let c = get_or_run(&self.dockercli, &self.image_name).expect("get or run");
-        let image_c = run(&self.dockercli, &self.image_name).expect("run");
-        let c_checksum = self.get_checksum_str(&c.id);
-        let image_c_checksum = self.get_checksum_str(&image_c.id);
-        c_checksum != image_c_checksum
+    /// cached_stamp tell which image and when the cached index is of
+    pub fn cached_stamp(&self) -> Result<metacache::Stamp, Error> {
+        let cache = self
+            .meta_cache
+            .as_ref()
+            .ok_or_else(|| Error::NotCached(self.image_name.clone()))?;
+        cache
+            .stamp(&self.image_name)
+            .map_err(|e| Error::MetaCacheError(e.to_string()))?
+            .ok_or_else(|| Error::NotCached(self.image_name.clone()))
+    }
+    fn cached_index(&self) -> Result<metacache::Index, Error> {
+        self.cached_stamp()?;
+        self.meta_cache
+            .as_ref()
+            .unwrap()
+            .load(&self.image_name)
+            .map_err(|e| Error::MetaCacheError(e.to_string()))
+    }
+    /// cached_entries is `entries` from the meta cache
+    pub fn cached_entries(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .cached_index()?
+            .entries
+            .iter()
+            .map(|e| self.display_name(e))
+            .collect())
+    }
+    /// cached_item_infos is `item_infos` from the meta cache
+    pub fn cached_item_infos(&self, entry: &str) -> Result<Vec<ItemInfo>, Error> {
+        let index = self.cached_index()?;
+        let entry = self.store_name(entry)?;
+        if !index.entries.contains(&entry) {
+            return Err(Error::NotFoundEntry(entry));
+        }
+        Ok(self
+            .found(index.records, |e, _| e == entry)
+            .into_iter()
+            .map(|(_, info)| info)
+            .collect())
+    }
+    /// cached_status is `status` from the meta cache, the remote state is unknown
+    pub fn cached_status(&self) -> Result<Status, Error> {
+        Ok(Status {
+            changes: self.display_changes(self.cached_index()?.changes),
+            remote: RemoteState::Unknown,
+        })
+    }
+    /// find items of every entry whose name matches `pattern`, see `utils::matches`
+    pub fn find(&self, pattern: &str) -> Result<Vec<(String, ItemInfo)>, Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let out = exec(&self.dockercli, &c.id, index::LIST_CMD)?;
+        Ok(self.found(index::parse_records(&out), |_, item| matches(pattern, item)))
+    }
+    /// cached_find is `find` in the meta cache
+    pub fn cached_find(&self, pattern: &str) -> Result<Vec<(String, ItemInfo)>, Error> {
+        Ok(self.found(self.cached_index()?.records, |_, item| {
+            matches(pattern, item)
+        }))
+    }
+    /// found turn `records` into display names of entries and items,
+    /// keeping those `keep` accepts with their display names
+    fn found<F: Fn(&str, &str) -> bool>(
+        &self,
+        records: std::collections::BTreeMap<String, index::Record>,
+        keep: F,
+    ) -> Vec<(String, ItemInfo)> {
+        records
+            .into_iter()
+            .filter_map(|(key, record)| {
+                let pos = key.find('/')?;
+                let entry = self.display_name(&key[..pos]);
+                let item = self.display_name(&key[pos + 1..]);
+                if !keep(&entry, &item) {
+                    return None;
+                }
+                let info = ItemInfo {
+                    name: item,
+                    checksum: record.checksum,
+                    size: record.size,
+                    stored: record.stored,
+                    dir: record.dir,
+                };
+                Some((entry, info))
+            })
+            .collect()
+    }
+    fn remote_state(&self) -> RemoteState {
+        let local = match self.dockercli.image_digests(&self.image_name) {
+            Ok(ds) => ds,
+            Err(_) => return RemoteState::Unknown,
+        };
+        let remote = match self.dockercli.remote_digests(&self.image_name) {
+            Ok(ds) => ds,
+            Err(_) => return RemoteState::Unknown,
+        };
+        if local.is_empty() || remote.is_empty() {
+            return RemoteState::Unknown;
+        }
+        // RepoDigests look like `repo@sha256:...`
+        let same = local.iter().any(|l| {
+            remote
+                .iter()
+                .any(|r| l.rsplit('@').next() == Some(r.as_str()))
+        });
+        if same {
+            RemoteState::UpToDate
+        } else {
+            RemoteState::Differ
+        }
     }
 }
 
@@ -73,115 +927,308 @@ impl fmt::Display for ImageDrive {
 
 impl DB<Error> for ImageDrive {
     fn entries(&self) -> Result<Vec<String>, Error> {
-        ls(&self.dockercli, &self.image_name, &Path::new("/data"))
+        ls(&self.dockercli, &self.image_name, Path::new("/data"))
+            .map(|names| names.iter().map(|n| self.display_name(n)).collect())
     }
     fn items(&self, entry: &str) -> Result<Vec<String>, Error> {
         ls(
             &self.dockercli,
             &self.image_name,
-            &Path::new("/data").join(entry),
+            &Path::new("/data").join(self.store_name(entry)?),
         )
+        .map(|names| names.iter().map(|n| self.display_name(n)).collect())
     }
 
-    fn add(&self, entry: &str, itempath: &Path, rename: Option<&str>) -> Result<AddResult, Error> {
-        if !itempath.exists() {
-            return Err(Error::NotExistItem(format!("{}", itempath.display())));
+    fn add(
+        &self,
+        entry: &str,
+        itempath: &Path,
+        rename: Option<&str>,
+        conflict: Conflict,
+    ) -> Result<AddResult, Error> {
+        self.put_items(entry, &[itempath], rename, conflict, true)?
+            .pop()
+            .map(|(_, result)| result)
+            .unwrap_or_else(|| Err(Error::NotExistItem(itempath.display().to_string())))
+    }
+    fn add_many(
+        &self,
+        entry: &str,
+        itempaths: &[&Path],
+        conflict: Conflict,
+    ) -> Result<AddResults<Error>, Error> {
+        self.put_items(entry, itempaths, None, conflict, true)
+    }
+    fn mirror_push(&self, dir: &Path, entry: &str, delete: bool) -> Result<MirrorReport, Error> {
+        let stored = self.entry_checksums(entry, true)?;
+        let mut report = MirrorReport::default();
+        let mut changed = vec![];
+        let mut names = vec![];
+        let mut paths = vec![];
+        for e in std::fs::read_dir(dir).map_err(Error::IOError)? {
+            paths.push(e.map_err(Error::IOError)?.path());
+        }
+        let sums = self.host_hashes(&paths, &self.algorithms(stored.values().flatten()));
+        for (path, sums) in paths.into_iter().zip(sums) {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let sums = sums?;
+            if stored.get(&name).is_some_and(|s| index::same(s, &sums)) {
+                report.unchanged += 1;
+            } else {
+                changed.push(path);
+            }
+            names.push(name);
+        }
+        let paths: Vec<&Path> = changed.iter().map(|p| p.as_path()).collect();
+        for (path, result) in self.put_items(entry, &paths, None, Conflict::Overwrite, false)? {
+            result?;
+            report
+                .transferred
+                .push(path.file_name().unwrap().to_string_lossy().to_string());
+        }
+        if delete {
+            for name in stored.keys().filter(|n| !names.contains(n)) {
+                self.delete(entry, Some(name))?;
+                report.deleted.push(name.clone());
+            }
         }
-        let mut item = hostitem::HostItem::new(itempath, rename).map_err(Error::HostItemError)?;
+        Ok(report)
+    }
 
-        let c = get_or_run(&self.dockercli, &self.image_name).map_err(Error::DockerError)?;
-        let path = Path::new("/data").join(entry);
-        let ls_entry = format!(
-            "mkdir -p {} && ls {}",
-            path.as_path().display(),
-            path.as_path().display()
-        );
-        self.dockercli
-            .exec(&c.id, &ls_entry)
-            .map_err(Error::DockerError)
-            .map(|(out, err)| {
-                (
-                    (String::from_utf8_lossy(&out).to_owned().to_string()),
-                    (String::from_utf8_lossy(&err).to_owned().to_string()),
-                )
-            })
-            .and_then(|(out, err)| {
-                if err != "" {
-                    return Err(Error::ExecError(err));
-                }
-                let items: Vec<&str> = if out.trim() == "" {
-                    vec![]
-                } else {
-                    out.trim().split_whitespace().collect()
-                };
-                let mut ps = vec![];
-                let mut fileitems = vec![];
-                for item in items {
-                    ps.push(Path::new("/data").join(entry).join(item));
+    fn mirror_pull(&self, entry: &str, dir: &Path, delete: bool) -> Result<MirrorReport, Error> {
+        // a missing entry is an error, else `delete` would empty `dir`
+        let stored = self.entry_checksums(entry, false)?;
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        std::fs::create_dir_all(dir).map_err(Error::IOError)?;
+        let mut report = MirrorReport::default();
+        for (name, sums) in &stored {
+            let path = dir.join(name);
+            if path.exists() && !sums.is_empty() {
+                let host = hostitem::HostItem::new(&path, None)
+                    .and_then(|item| {
+                        item.with_jobs(self.jobs)
+                            .with_algorithms(&[hash::algorithm_of(&sums[0])])
+                            .with_cache(self.hash_cache.as_ref())
+                            .checksums()
+                    })
+                    .map(|host| self.keyed(host))
+                    .map_err(Error::HostItemError)?;
+                if index::same(sums, &host) {
+                    report.unchanged += 1;
+                    continue;
                 }
-                for p in &ps {
-                    fileitems.push(
-                        containeritem::ContainerItem::new(&p, &self.dockercli, &self.image_name)
-                            .map_err(Error::ContainerItemError)?,
-                    );
-                }
-
-                for mut fi in fileitems {
-                    if compare_items(&mut fi, &mut item) {
-                        return Ok(AddResult::ExistedItem(fi.id().into()));
-                    }
+            }
+            // fetched next to the destination, so replacing it is a rename
+            let tmp = tempfile::tempdir_in(dir).map_err(Error::IOError)?;
+            let fetched = tmp.path().join("item");
+            let src = Path::new("/data")
+                .join(self.store_name(entry)?)
+                .join(self.store_name(name)?);
+            self.dockercli
+                .copy_out(&c.id, &src, &fetched)
+                .map_err(Error::DockerError)?;
+            self.codec()
+                .decode_in_place(&fetched, &|recipe| self.fetch_chunks(&c.id, recipe))
+                .map_err(Error::IOError)?;
+            remove_host_path(&path)?;
+            std::fs::rename(&fetched, &path).map_err(Error::IOError)?;
+            report.transferred.push(name.clone());
+        }
+        if delete {
+            for e in std::fs::read_dir(dir).map_err(Error::IOError)? {
+                let path = e.map_err(Error::IOError)?.path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                if !stored.contains_key(&name) {
+                    remove_host_path(&path)?;
+                    report.deleted.push(name);
                 }
-                let dstpath = Path::new("/data").join(entry).join(item.id());
-                self.dockercli
-                    .copy_in(&c.id, item.srcpath(), &dstpath)
-                    .map_err(Error::DockerError)?;
-
-                self.dockercli
-                    .exec(
-                        &c.id,
-                        &format!(
-                            "mkdir -p {} && echo {} > {}",
-                            Path::new("/checksum")
-                                .join(dstpath.parent().unwrap().strip_prefix("/").unwrap())
-                                .display(),
-                            hex::encode(item.hash()),
-                            Path::new("/checksum")
-                                .join(dstpath.strip_prefix("/").unwrap())
-                                .display()
-                        ),
-                    )
-                    .expect("write checksum");
-                Ok(AddResult::Succ)
-            })
+            }
+        }
+        Ok(report)
     }
+
     fn delete(&self, entry: &str, item: Option<&str>) -> Result<(), Error> {
-        let c = get_or_run(&self.dockercli, &self.image_name).map_err(Error::DockerError)?;
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
         let dstpath = match item {
-            None => Path::new("/data").join(entry),
-            Some(file) => Path::new("/data").join(entry).join(file),
+            None => Path::new("/data").join(self.store_name(entry)?),
+            Some(file) => Path::new("/data")
+                .join(self.store_name(entry)?)
+                .join(self.store_name(file)?),
         };
-        self.dockercli
-            .remove_file(&c.id, &dstpath)
-            .map_err(|e| Error::ExecError(format!("{:?}", e)))
+        let checksum_path = Path::new("/checksum").join(dstpath.strip_prefix("/").unwrap());
+        let trash_dir = Path::new(trash::TRASH_DIR).join(trash::dir_name(now()));
+        let trash_data = trash_dir.join(dstpath.parent().unwrap().strip_prefix("/").unwrap());
+        let trash_checksum =
+            trash_dir.join(checksum_path.parent().unwrap().strip_prefix("/").unwrap());
+        // data and checksum go in one command, so neither is left without the other
+        let out = exec(
+            &self.dockercli,
+            &c.id,
+            &format!(
+                "if [ -e {} ]; then mkdir -p {} {} && mv {} {} && \
+                 (mv {} {} 2>/dev/null; true); else echo missing; fi",
+                quote(&dstpath),
+                quote(&trash_data),
+                quote(&trash_checksum),
+                quote(&dstpath),
+                quote(&trash_data),
+                quote(&checksum_path),
+                quote(&trash_checksum)
+            ),
+        )?;
+        if out.trim() == "missing" {
+            return Err(match item {
+                None => Error::NotFoundEntry(entry.to_owned()),
+                Some(file) => Error::NotFoundItem(format!("{}/{}", entry, file)),
+            });
+        }
+        Ok(())
+    }
+
+    fn rename(&self, entry: &str, item: &str, new_name: &str) -> Result<(), Error> {
+        if !valid_item_name(new_name) {
+            return Err(Error::BadItemName(new_name.to_owned()));
+        }
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let dir = Path::new("/data").join(self.store_name(entry)?);
+        let src = dir.join(self.store_name(item)?);
+        let dst = dir.join(self.store_name(new_name)?);
+        let checksum = |p: &Path| Path::new("/checksum").join(p.strip_prefix("/").unwrap());
+        let out = exec(
+            &self.dockercli,
+            &c.id,
+            &format!(
+                "if [ ! -e {} ]; then echo missing; elif [ -e {} ]; then echo existed; \
+                 else mv {} {} && (mv {} {} 2>/dev/null; true); fi",
+                quote(&src),
+                quote(&dst),
+                quote(&src),
+                quote(&dst),
+                quote(checksum(&src)),
+                quote(checksum(&dst))
+            ),
+        )?;
+        match out.trim() {
+            "missing" => Err(Error::NotFoundItem(format!("{}/{}", entry, item))),
+            "existed" => Err(Error::ExistedItem(format!("{}/{}", entry, new_name))),
+            _ => Ok(()),
+        }
     }
 
     fn export_to_dir(&self, dir: &Path, entry: &str) -> Result<(), Error> {
-        let c = get_or_run(&self.dockercli, &self.image_name).map_err(Error::DockerError)?;
-        let srcpath = Path::new("/data").join(entry);
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let srcpath = Path::new("/data").join(self.store_name(entry)?);
+        // same as `docker cp`: into `dir` if it exists, else as `dir`
+        let dst = if dir.exists() {
+            dir.join(self.store_name(entry)?)
+        } else {
+            dir.to_path_buf()
+        };
         self.dockercli
             .copy_out(&c.id, &srcpath, dir)
-            .map_err(Error::DockerError)
+            .map_err(Error::DockerError)?;
+        self.codec()
+            .decode_in_place(&dst, &|recipe| self.fetch_chunks(&c.id, recipe))
+            .map_err(Error::IOError)?;
+        if dir.exists() && self.store_name(entry)? != entry {
+            std::fs::rename(&dst, dir.join(entry)).map_err(Error::IOError)?;
+        }
+        Ok(())
+    }
+
+    fn cat(&self, entry: &str, item: &str, out: &mut dyn std::io::Write) -> Result<(), Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let path = Path::new("/data")
+            .join(self.store_name(entry)?)
+            .join(self.store_name(item)?);
+        let (content, err) = self
+            .dockercli
+            .exec(&c.id, &format!("cat {}", quote(&path)))
+            .map_err(Error::DockerError)?;
+        if !err.is_empty() {
+            return Err(Error::ExecError(String::from_utf8_lossy(&err).to_string()));
+        }
+        if let Some(recipe) = chunk::Recipe::parse(&content) {
+            let chunks = self.fetch_chunks(&c.id, &recipe).map_err(Error::IOError)?;
+            return chunk::assemble(&recipe, chunks, &self.codec(), out).map_err(Error::IOError);
+        }
+        let mut r = self
+            .codec()
+            .decode(std::io::Cursor::new(content))
+            .map_err(Error::IOError)?;
+        std::io::copy(&mut r, out)
+            .map(|_| ())
+            .map_err(Error::IOError)
+    }
+
+    fn item_infos(&self, entry: &str) -> Result<Vec<ItemInfo>, Error> {
+        Ok(self
+            .entry_records(entry)?
+            .into_iter()
+            .map(|(name, record)| ItemInfo {
+                name,
+                checksum: record.checksum,
+                size: record.size,
+                stored: record.stored,
+                dir: record.dir,
+            })
+            .collect())
     }
 
-    fn sync(&self) -> Result<(), Error> {
+    fn sync(&self, message: Option<&str>) -> Result<(), Error> {
         // 1. commit all changed data in container to image
-        let c = get_or_run(&self.dockercli, &self.image_name).map_err(Error::DockerError)?;
-        if self.diff_container_with_image() {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let current = self.index(&c.id)?;
+        let committed = self.committed_index(&c.id)?;
+        let changes = index::diff(&committed, &current);
+        if !changes.is_empty() {
             println!("something changed in localDB , so need to sync to remote");
-            let _ = self
-                .dockercli
-                .commit(&c.id, "commit by sync", &self.image_name)
-                .map_err(Error::DockerError)?;
+            let log = self.read_file(&c.id, history::LOG_PATH)?;
+            let mut revision = Revision {
+                rev: history::parse_log(&log).last().map(|r| r.rev + 1).unwrap_or(1),
+                time: now().as_secs(),
+                message: message.unwrap_or("commit by sync").to_owned(),
+                added: 0,
+                modified: 0,
+                deleted: 0,
+            };
+            for change in &changes {
+                match change {
+                    Change::Added(_) => revision.added += 1,
+                    Change::Modified(_) => revision.modified += 1,
+                    Change::Deleted(_) => revision.deleted += 1,
+                }
+            }
+            let history_path = format!("{}/{}", history::HISTORY_DIR, revision.rev);
+            // committed with the data, so they are put back if the commit fails
+            let old_committed = self.read_file(&c.id, index::COMMITTED_PATH)?;
+            self.write_meta(
+                &c.id,
+                &[
+                    (index::COMMITTED_PATH, index::format_committed(&current)),
+                    (history::LOG_PATH, log.clone() + &history::format_revision(&revision)),
+                    (&history_path, history::format_changes(&changes)),
+                ],
+            )?;
+            if let Err(e) = self.dockercli.commit(
+                &c.id,
+                &history::commit_message(revision.rev, &revision.message),
+                &self.image_name,
+            ) {
+                let _ = self.write_meta(
+                    &c.id,
+                    &[
+                        (index::COMMITTED_PATH, old_committed),
+                        (history::LOG_PATH, log),
+                    ],
+                );
+                let _ = exec(
+                    &self.dockercli,
+                    &c.id,
+                    &format!("rm -f {}", quote(&history_path)),
+                );
+                return Err(Error::DockerError(e));
+            }
         }
 
         // 2. push image
@@ -200,26 +1247,316 @@ impl DB<Error> for ImageDrive {
             .pull(&self.image_name)
             .map_err(Error::DockerError)
     }
+
+    fn status(&self) -> Result<Status, Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let current = self.index(&c.id)?;
+        let committed = self.committed_index(&c.id)?;
+        Ok(Status {
+            changes: self.display_changes(index::diff(&committed, &current)),
+            remote: self.remote_state(),
+        })
+    }
+
+    fn log(&self) -> Result<Vec<Revision>, Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        self.read_file(&c.id, history::LOG_PATH)
+            .map(|log| history::parse_log(&log))
+    }
+
+    fn show(&self, rev: u32) -> Result<(Revision, Vec<Change>), Error> {
+        self.revision(rev)
+            .map(|(revision, changes)| (revision, self.display_changes(changes)))
+    }
+
+    fn checkout(&self, rev: u32, force: bool) -> Result<(), Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        self.ensure_synced(&c.id, force)?;
+        let (image, _) = self.revision_images(rev)?;
+        self.restore_image(&c.id, &image)
+    }
+
+    fn revert(&self, rev: u32, force: bool) -> Result<Vec<Change>, Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        self.ensure_synced(&c.id, force)?;
+        let (_, parent) = self.revision_images(rev)?;
+        let (_, changes) = self.revision(rev)?;
+        let src = run(&self.dockercli, &parent).map_err(Error::DockerError)?;
+        let tmp = tempfile::tempdir().map_err(Error::IOError)?;
+        // everything is copied out of the parent first, so a failed copy leaves localDB as is
+        let mut undos = vec![];
+        let mut ids = std::collections::BTreeSet::new();
+        for change in changes {
+            let (key, undo) = match change {
+                Change::Added(key) => (key.clone(), Change::Deleted(key)),
+                Change::Modified(key) => (key.clone(), Change::Modified(key)),
+                Change::Deleted(key) => (key.clone(), Change::Added(key)),
+            };
+            let data = Path::new("/data").join(&key);
+            let checksum = Path::new("/checksum/data").join(&key);
+            if let Change::Deleted(_) = undo {
+                undos.push((data, checksum, undo));
+                continue;
+            }
+            // bring back the item as it was before `rev`
+            for path in &[&data, &checksum] {
+                let hostpath = tmp.path().join(path.strip_prefix("/").unwrap());
+                std::fs::create_dir_all(hostpath.parent().unwrap()).map_err(Error::IOError)?;
+                self.dockercli
+                    .copy_out(&src.id, path, &hostpath)
+                    .map_err(Error::DockerError)?;
+            }
+            let hostdata = tmp.path().join(data.strip_prefix("/").unwrap());
+            ids.extend(chunk::recipe_ids(&hostdata).map_err(Error::IOError)?);
+            undos.push((data, checksum, undo));
+        }
+        // chunks removed since `rev`, e.g. by emptying the trash, are still in the parent
+        let existing = chunk::parse_listing(&exec(&self.dockercli, &c.id, chunk::LIST_CMD)?);
+        let missing: Vec<&String> = ids
+            .iter()
+            .filter(|id| !existing.contains_key(*id))
+            .collect();
+        for id in &missing {
+            let path = chunk::chunk_path(id);
+            let hostpath = tmp.path().join(path.trim_start_matches('/'));
+            std::fs::create_dir_all(hostpath.parent().unwrap()).map_err(Error::IOError)?;
+            self.dockercli
+                .copy_out(&src.id, Path::new(&path), &hostpath)
+                .map_err(Error::DockerError)?;
+        }
+        if !missing.is_empty() {
+            // merged like in `restore_image`, before the recipes which refer to them
+            exec(&self.dockercli, &c.id, "mkdir -p /chunks")?;
+            self.dockercli
+                .copy_in(
+                    &c.id,
+                    &tmp.path().join("chunks").join("."),
+                    Path::new("/chunks"),
+                )
+                .map_err(Error::DockerError)?;
+        }
+        let mut reverted = vec![];
+        for (data, checksum, undo) in undos {
+            exec(
+                &self.dockercli,
+                &c.id,
+                &format!(
+                    "rm -rf {} {} && mkdir -p {} {}",
+                    quote(&data),
+                    quote(&checksum),
+                    quote(data.parent().unwrap()),
+                    quote(checksum.parent().unwrap())
+                ),
+            )?;
+            if let Change::Deleted(_) = undo {
+                reverted.push(undo);
+                continue;
+            }
+            for path in &[&data, &checksum] {
+                let hostpath = tmp.path().join(path.strip_prefix("/").unwrap());
+                self.dockercli
+                    .copy_in(&c.id, &hostpath, path)
+                    .map_err(Error::DockerError)?;
+            }
+            reverted.push(undo);
+        }
+        Ok(self.display_changes(reverted))
+    }
+
+    fn snapshot_create(&self, name: &str) -> Result<(), Error> {
+        let snapshot = self.snapshot_image(name)?;
+        self.dockercli
+            .tag(&self.image_name, &snapshot)
+            .map_err(Error::DockerError)?;
+        self.dockercli.push(&snapshot).map_err(Error::DockerError)
+    }
+
+    fn snapshots(&self) -> Result<Vec<Snapshot>, Error> {
+        let (repo, tag) = repository(&self.image_name);
+        let tags = self.dockercli.tags(repo).map_err(Error::DockerError)?;
+        Ok(tags
+            .into_iter()
+            .filter(|(name, _)| Some(name.as_str()) != tag)
+            .map(|(name, created)| Snapshot { name, created })
+            .collect())
+    }
+
+    fn snapshot_restore(&self, name: &str, force: bool) -> Result<(), Error> {
+        let snapshot = self.snapshot_image(name)?;
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        self.ensure_synced(&c.id, force)?;
+        if self.dockercli.image(&snapshot).is_err() {
+            self.dockercli.pull(&snapshot).map_err(Error::DockerError)?;
+        }
+        self.restore_image(&c.id, &snapshot)
+    }
+
+    fn trash(&self) -> Result<Vec<TrashItem>, Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let out = exec(&self.dockercli, &c.id, trash::LIST_CMD)?;
+        Ok(trash::parse_listing(&out)
+            .into_iter()
+            .map(|d| TrashItem {
+                entry: self.display_name(&d.entry),
+                item: self.display_name(&d.item),
+                deleted: d.time,
+            })
+            .collect())
+    }
+
+    fn trash_restore(&self, entry: &str, item: &str) -> Result<(), Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let (entry, item) = (self.store_name(entry)?, self.store_name(item)?);
+        let out = exec(&self.dockercli, &c.id, trash::LIST_CMD)?;
+        let deleted = trash::parse_listing(&out)
+            .into_iter()
+            .find(|d| d.entry == entry && d.item == item)
+            .ok_or_else(|| {
+                Error::NotFoundItem(format!(
+                    "{}/{}",
+                    self.display_name(&entry),
+                    self.display_name(&item)
+                ))
+            })?;
+        let dstpath = Path::new("/data").join(&entry).join(&item);
+        let checksum_path = Path::new("/checksum").join(dstpath.strip_prefix("/").unwrap());
+        let trash_dir = Path::new(trash::TRASH_DIR).join(&deleted.dir);
+        let out = exec(
+            &self.dockercli,
+            &c.id,
+            &format!(
+                "if [ -e {} ]; then echo existed; else mkdir -p {} {} && mv {} {} && \
+                 (mv {} {} 2>/dev/null; true); fi",
+                quote(&dstpath),
+                quote(dstpath.parent().unwrap()),
+                quote(checksum_path.parent().unwrap()),
+                quote(deleted.data_path()),
+                quote(&dstpath),
+                quote(deleted.checksum_path()),
+                quote(&checksum_path)
+            ),
+        )?;
+        if out.trim() == "existed" {
+            return Err(Error::ExistedItem(format!(
+                "{}/{}",
+                self.display_name(&entry),
+                self.display_name(&item)
+            )));
+        }
+        // drop trash dirs left empty, rmdir keeps those which still hold items
+        let dirs = [
+            trash_dir.join("data").join(&entry),
+            trash_dir.join("data"),
+            trash_dir.join("checksum/data").join(&entry),
+            trash_dir.join("checksum/data"),
+            trash_dir.join("checksum"),
+            trash_dir.clone(),
+        ];
+        let dirs: Vec<String> = dirs.iter().map(quote).collect();
+        exec(
+            &self.dockercli,
+            &c.id,
+            &format!("rmdir {} 2>/dev/null; true", dirs.join(" ")),
+        )?;
+        Ok(())
+    }
+
+    fn trash_empty(&self, older_than: Option<u64>) -> Result<usize, Error> {
+        let c = get_or_run(&self.dockercli, &self.image_name)?;
+        let out = exec(&self.dockercli, &c.id, trash::LIST_CMD)?;
+        let deadline = now().as_secs().saturating_sub(older_than.unwrap_or(0));
+        let expired: Vec<trash::Deleted> = trash::parse_listing(&out)
+            .into_iter()
+            .filter(|d| older_than.is_none() || d.time < deadline)
+            .collect();
+        let mut dirs: Vec<String> = expired
+            .iter()
+            .map(|d| quote(Path::new(trash::TRASH_DIR).join(&d.dir)))
+            .collect();
+        dirs.dedup();
+        if older_than.is_none() {
+            dirs = vec![quote(trash::TRASH_DIR)];
+        }
+        if !dirs.is_empty() {
+            exec(
+                &self.dockercli,
+                &c.id,
+                &format!("rm -rf {}", dirs.join(" ")),
+            )?;
+            // items replaced or deleted keep their chunks until they leave the trash
+            self.gc_chunks(&c.id)?;
+        }
+        Ok(expired.len())
+    }
+}
+
+/// remove_host_path remove file or dir `path` on host, if it exists
+fn remove_host_path(path: &Path) -> Result<(), Error> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path).map_err(Error::IOError)
+    } else if path.exists() {
+        std::fs::remove_file(path).map_err(Error::IOError)
+    } else {
+        Ok(())
+    }
+}
+
+/// now is the time since unix epoch
+/// valid_item_name is false for names which are not a single path component
+pub fn valid_item_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && name != "." && name != ".."
+}
+
+fn now() -> std::time::Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// record_cmd is the shell command writing `record` to checksum file `path`
+fn record_cmd(path: &Path, record: &index::Record) -> String {
+    record
+        .format()
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let redirect = if i == 0 { ">" } else { ">>" };
+            format!("echo {} {} {}", quote(line), redirect, quote(path))
+        })
+        .collect::<Vec<String>>()
+        .join(" && ")
+}
+
+fn exec(cli: &dockerclient::DockerClient, container: &str, cmd: &str) -> Result<String, Error> {
+    cli.exec(container, cmd)
+        .map_err(Error::DockerError)
+        .and_then(|(out, err)| {
+            let err = String::from_utf8_lossy(&err);
+            if !err.is_empty() {
+                return Err(Error::ExecError(err.to_string()));
+            }
+            Ok(String::from_utf8_lossy(&out).to_string())
+        })
 }
 
 fn ls(cli: &dockerclient::DockerClient, image: &str, dir: &Path) -> Result<Vec<String>, Error> {
-    let c = get_or_run(cli, image).map_err(Error::DockerError)?;
-    cli.exec(&c.id, &format!("ls {}", dir.display()))
+    let c = get_or_run(cli, image)?;
+    cli.exec(&c.id, &format!("ls {}", quote(dir)))
         .map_err(Error::DockerError)
         .map(|(out, err)| {
             (
-                String::from_utf8_lossy(&out).to_owned().to_string(),
-                String::from_utf8_lossy(&err).to_owned().to_string(),
+                String::from_utf8_lossy(&out).to_string(),
+                String::from_utf8_lossy(&err).to_string(),
             )
         })
         .and_then(|(out, err)| {
-            if err != "" {
+            if !err.is_empty() {
                 return Err(Error::ExecError(err));
             }
             if out.trim() == "" {
                 return Ok(vec![]);
             }
-            let dirs: Vec<&str> = out.trim().split_whitespace().collect();
+            let dirs: Vec<&str> = out.split_whitespace().collect();
             let mut r = vec![];
             for dir in dirs {
                 r.push(dir.trim().to_owned().to_string());
@@ -227,3 +1564,55 @@ fn ls(cli: &dockerclient::DockerClient, image: &str, dir: &Path) -> Result<Vec<S
             Ok(r)
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// no_docker is true when there is no docker daemon to run these tests with
+    fn no_docker() -> bool {
+        let host = std::env::var("DOCKER_HOST").unwrap_or_default();
+        let socket = match host.trim_start_matches("unix://") {
+            "" => "/var/run/docker.sock",
+            socket => socket,
+        };
+        !Path::new(socket).exists()
+    }
+
+    #[test]
+    fn revert_brings_back_chunks() {
+        if no_docker() {
+            return;
+        }
+        let image = "imagedrive-test-revert:latest";
+        let cli = dockerclient::DockerClient::new();
+        if cli.image("busybox:latest").is_err() {
+            cli.pull("busybox:latest").unwrap();
+        }
+        cli.tag("busybox:latest", image).unwrap();
+        let drive = ImageDrive::open(image).with_chunk_threshold(1024);
+        let dir = tempfile::tempdir().unwrap();
+        let item = dir.path().join("big");
+        let content: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(&item, &content).unwrap();
+
+        let result = (|| -> Result<Vec<u8>, Error> {
+            drive.add("e", &item, None, Conflict::NoClobber)?;
+            // there is no registry to push to, the revision is committed before
+            let _ = drive.sync(Some("add"));
+            drive.delete("e", Some("big"))?;
+            let _ = drive.sync(Some("delete"));
+            drive.trash_empty(None)?;
+            let rev = drive.log()?.last().map(|r| r.rev).unwrap_or(0);
+            drive.revert(rev, true)?;
+            let mut out = vec![];
+            drive.cat("e", "big", &mut out)?;
+            Ok(out)
+        })();
+        if let Ok(c) = get_or_run(&drive.dockercli, image) {
+            let _ = cli.remove(&c.id);
+        }
+        let _ = cli.remove_image(image);
+        assert_eq!(result.unwrap(), content);
+    }
+}
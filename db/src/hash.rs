@@ -0,0 +1,135 @@
+use sha2::{Digest, Sha256};
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Algorithm of item checksums. A checksum is stored as `<algorithm>:<hex digest>`,
+/// checksums written by old imagedrive are a bare sha256 hex digest
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Algorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "sha256" => Ok(Algorithm::Sha256),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => Err(format!("unknown hash: {}, use sha256 or blake3", s)),
+        }
+    }
+}
+
+pub enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    /// finish is the checksum of everything written, as stored
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(h) => format(Algorithm::Sha256, &h.result()),
+            Hasher::Blake3(h) => format(Algorithm::Blake3, h.finalize().as_bytes()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Hasher::Sha256(h) => h.input(buf),
+            Hasher::Blake3(h) => {
+                h.update(buf);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// format `digest` of `algorithm` as stored
+pub fn format(algorithm: Algorithm, digest: &[u8]) -> String {
+    format!("{}:{}", algorithm.name(), hex::encode(digest))
+}
+
+/// parse a stored checksum, None if it is not one
+pub fn parse(checksum: &str) -> Option<(Algorithm, Vec<u8>)> {
+    let (algorithm, digest) = match checksum.find(':') {
+        Some(pos) => (checksum[..pos].parse().ok()?, &checksum[pos + 1..]),
+        None => (Algorithm::Sha256, checksum),
+    };
+    hex::decode(digest).ok().map(|digest| (algorithm, digest))
+}
+
+/// normalize give a bare sha256 checksum of old imagedrive its algorithm,
+/// so old and new checksums of the same content are equal
+pub fn normalize(checksum: &str) -> String {
+    match parse(checksum) {
+        Some((algorithm, digest)) => format(algorithm, &digest),
+        None => checksum.to_owned(),
+    }
+}
+
+/// algorithm_of checksum, old bare checksums are sha256
+pub fn algorithm_of(checksum: &str) -> Algorithm {
+    parse(checksum)
+        .map(|(algorithm, _)| algorithm)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_checksums() {
+        assert_eq!(
+            parse("blake3:00ff"),
+            Some((Algorithm::Blake3, vec![0, 255]))
+        );
+        assert_eq!(parse("00ff"), Some((Algorithm::Sha256, vec![0, 255])));
+        assert_eq!(parse("md5:00ff"), None);
+        assert_eq!(parse("size=10"), None);
+        assert_eq!(normalize("00ff"), "sha256:00ff");
+        assert_eq!(normalize("sha256:00ff"), "sha256:00ff");
+    }
+
+    #[test]
+    fn hashers() {
+        let mut h = Algorithm::Sha256.hasher();
+        h.write_all(b"abc").unwrap();
+        assert_eq!(
+            h.finish(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let mut h = Algorithm::Blake3.hasher();
+        h.write_all(b"abc").unwrap();
+        assert_eq!(
+            h.finish(),
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+}
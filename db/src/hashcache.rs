@@ -0,0 +1,173 @@
+use crate::hash::{self, Algorithm};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// HashCache remember checksums of host items, so unchanged items aren't read again.
+/// It is a file of `<stamp>\t<checksum>,...\t<path>` lines, saved when it is dropped
+pub struct HashCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<PathBuf, (String, Vec<String>)>>,
+    changed: AtomicBool,
+}
+
+impl HashCache {
+    /// open the cache at `path`, a missing or unreadable cache is empty
+    pub fn open(path: &Path) -> HashCache {
+        let entries = fs::read_to_string(path)
+            .map(|content| parse(&content))
+            .unwrap_or_default();
+        HashCache {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    /// get checksums of `path` with each of `algorithms`, if its stamp is still `stamp`
+    pub fn get(&self, path: &Path, stamp: &str, algorithms: &[Algorithm]) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap();
+        let (cached, sums) = entries.get(path)?;
+        if cached != stamp {
+            return None;
+        }
+        algorithms
+            .iter()
+            .map(|a| {
+                sums.iter()
+                    .find(|sum| hash::algorithm_of(sum) == *a)
+                    .cloned()
+            })
+            .collect()
+    }
+
+    pub fn put(&self, path: &Path, stamp: &str, sums: &[String]) {
+        if path.to_string_lossy().contains('\n') {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .entry(path.to_path_buf())
+            .or_insert_with(|| (stamp.to_owned(), vec![]));
+        if entry.0 != stamp {
+            *entry = (stamp.to_owned(), vec![]);
+        }
+        for sum in sums {
+            let algorithm = hash::algorithm_of(sum);
+            entry.1.retain(|s| hash::algorithm_of(s) != algorithm);
+            entry.1.push(sum.clone());
+        }
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// save the cache, entries of removed paths are dropped
+    pub fn save(&self) -> io::Result<()> {
+        if !self.changed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let entries = self.entries.lock().unwrap();
+        let mut content = String::new();
+        for (path, (stamp, sums)) in entries.iter() {
+            if path.exists() {
+                content.push_str(&format!(
+                    "{}\t{}\t{}\n",
+                    stamp,
+                    sums.join(","),
+                    path.display()
+                ));
+            }
+        }
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        // written aside and renamed, so a killed process never leaves half a cache.
+        // The temp file is unique, so processes saving at once don't mix their caches
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.persist(&self.path).map_err(|e| e.error)?;
+        self.changed.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for HashCache {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+/// stamp of the host item `path` made of `files`: inode, size and mtime of each file,
+/// so any change of content, or of the files of a directory, changes it
+pub fn stamp(path: &Path, files: &[PathBuf]) -> io::Result<String> {
+    let file = |p: &Path| -> io::Result<String> {
+        let m = fs::metadata(p)?;
+        Ok(format!(
+            "{}-{}-{}.{}",
+            m.ino(),
+            m.size(),
+            m.mtime(),
+            m.mtime_nsec()
+        ))
+    };
+    if path.is_file() {
+        return file(path);
+    }
+    let mut hasher = Sha256::new();
+    for f in files {
+        let name = f.strip_prefix(path).unwrap_or(f);
+        hasher.input(format!("{} {}\n", file(f)?, name.display()).as_bytes());
+    }
+    // `d2`: directories hash their file paths and lengths too, older `d` stamps are stale
+    Ok(format!("d2{}", hex::encode(hasher.result())))
+}
+
+fn parse(content: &str) -> HashMap<PathBuf, (String, Vec<String>)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let stamp = fields.next()?.to_owned();
+            let sums = fields.next()?.split(',').map(|s| s.to_owned()).collect();
+            Some((PathBuf::from(fields.next()?), (stamp, sums)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let item = dir.path().join("a b");
+        fs::write(&item, "1").unwrap();
+        let s1 = stamp(&item, &[]).unwrap();
+        let cache_path = dir.path().join("cache/hashes");
+        {
+            let cache = HashCache::open(&cache_path);
+            cache.put(&item, &s1, &["sha256:01".to_owned()]);
+            cache.put(&item, &s1, &["blake3:02".to_owned()]);
+        }
+        // nothing but the cache is left in its dir
+        let files: Vec<_> = fs::read_dir(dir.path().join("cache")).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let cache = HashCache::open(&cache_path);
+        assert_eq!(
+            cache.get(&item, &s1, &[Algorithm::Blake3, Algorithm::Sha256]),
+            Some(vec!["blake3:02".to_owned(), "sha256:01".to_owned()])
+        );
+        fs::write(&item, "22").unwrap();
+        let s2 = stamp(&item, &[]).unwrap();
+        assert_ne!(s1, s2);
+        assert_eq!(cache.get(&item, &s2, &[Algorithm::Sha256]), None);
+    }
+}
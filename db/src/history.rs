@@ -0,0 +1,126 @@
+use crate::{Change, Revision};
+
+/// path of the revision log inside the drive, one `format_revision` line per sync
+pub const LOG_PATH: &str = "/.imagedrive/log";
+/// changes of revision N are saved in `HISTORY_DIR/N`
+pub const HISTORY_DIR: &str = "/.imagedrive/history";
+
+/// commit_message is the image commit message of revision `rev`,
+/// `parse_commit_message` finds the revision in `docker history` by it
+pub fn commit_message(rev: u32, message: &str) -> String {
+    format!("[rev {}] {}", rev, message)
+}
+
+pub fn parse_commit_message(comment: &str) -> Option<u32> {
+    if !comment.starts_with("[rev ") {
+        return None;
+    }
+    let end = comment.find(']')?;
+    comment[5..end].parse().ok()
+}
+
+/// format_revision format `r` as a log line: `rev<TAB>time<TAB>added modified deleted<TAB>message`
+pub fn format_revision(r: &Revision) -> String {
+    format!(
+        "{}\t{}\t{} {} {}\t{}\n",
+        r.rev,
+        r.time,
+        r.added,
+        r.modified,
+        r.deleted,
+        r.message.replace('\n', " ")
+    )
+}
+
+pub fn parse_log(content: &str) -> Vec<Revision> {
+    let mut revs = vec![];
+    for line in content.lines() {
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        if fields.len() != 4 {
+            continue;
+        }
+        let counts: Vec<usize> = fields[2]
+            .split_whitespace()
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        if counts.len() != 3 {
+            continue;
+        }
+        if let (Ok(rev), Ok(time)) = (fields[0].parse(), fields[1].parse()) {
+            revs.push(Revision {
+                rev,
+                time,
+                message: fields[3].to_owned(),
+                added: counts[0],
+                modified: counts[1],
+                deleted: counts[2],
+            });
+        }
+    }
+    revs
+}
+
+/// format_changes format changes of a revision, one `A|M|D <entry>/<item>` per line
+pub fn format_changes(changes: &[Change]) -> String {
+    let mut s = String::new();
+    for change in changes {
+        let line = match change {
+            Change::Added(item) => format!("A {}\n", item),
+            Change::Modified(item) => format!("M {}\n", item),
+            Change::Deleted(item) => format!("D {}\n", item),
+        };
+        s.push_str(&line);
+    }
+    s
+}
+
+pub fn parse_changes(content: &str) -> Vec<Change> {
+    let mut changes = vec![];
+    for line in content.lines() {
+        if line.len() < 3 {
+            continue;
+        }
+        let item = line[2..].to_owned();
+        match &line[..2] {
+            "A " => changes.push(Change::Added(item)),
+            "M " => changes.push(Change::Modified(item)),
+            "D " => changes.push(Change::Deleted(item)),
+            _ => (),
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_message_roundtrip() {
+        assert_eq!(parse_commit_message(&commit_message(12, "add a")), Some(12));
+        assert_eq!(parse_commit_message("commit by sync"), None);
+    }
+
+    #[test]
+    fn log_roundtrip() {
+        let r = Revision {
+            rev: 3,
+            time: 1549000000,
+            message: "add\tdataset v2".to_owned(),
+            added: 1,
+            modified: 2,
+            deleted: 0,
+        };
+        let revs = parse_log(&format_revision(&r));
+        assert_eq!(revs, vec![r]);
+    }
+
+    #[test]
+    fn changes_roundtrip() {
+        let changes = vec![
+            Change::Added("e/a b".to_owned()),
+            Change::Deleted("e/c".to_owned()),
+        ];
+        assert_eq!(parse_changes(&format_changes(&changes)), changes);
+    }
+}
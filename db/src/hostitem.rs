@@ -1,66 +1,188 @@
-use sha2::{Digest, Sha256};
+use crate::hash::{self, Algorithm, Hasher};
+use crate::hashcache::{self, HashCache};
 use std::fs::File;
 use std::io;
-use std::io::{copy, Seek, SeekFrom};
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// files up to this size are read ahead in parallel when a directory is hashed,
+/// bigger ones are streamed
+const READ_AHEAD_MAX: u64 = 4 << 20;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     BadPath(String),
     IOError(io::Error),
     DefaultError(String),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::BadPath(p) => write!(f, "bad path: {}", p),
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::DefaultError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
 pub struct HostItem<'a> {
     path: &'a Path,
     id: String,
-    fs: Vec<File>, // if item is file, only 1 elem in vec
+    fs: Vec<PathBuf>, // if item is file, only 1 elem in vec
+    jobs: usize,
+    algorithms: Vec<Algorithm>,
+    cache: Option<&'a HashCache>,
 }
 
 impl<'a> HostItem<'a> {
     pub fn new(path: &'a Path, rename: Option<&str>) -> Result<Self, Error> {
-        let filename;
-        match path.file_name() {
+        let filename = match path.file_name() {
             None => {
                 return Err(Error::BadPath(path.display().to_string()));
             }
-            Some(s) => filename = s.to_string_lossy().to_owned(),
-        }
+            Some(s) => s.to_string_lossy(),
+        };
         let mut id = format!("{:?}", filename);
-        if rename.is_some() {
-            id = format!("{:?}", rename.unwrap())
+        if let Some(rename) = rename {
+            id = format!("{:?}", rename)
         }
         let mut fs = vec![];
         // host file or dir
         if path.is_file() {
-            let f = File::open(path).map_err(Error::IOError)?;
-            fs.push(f);
+            fs.push(path.to_path_buf());
         } else if path.is_dir() {
             for e in walkdir::WalkDir::new(path)
                 .follow_links(true)
                 .sort_by(|a, b| a.file_name().cmp(b.file_name()))
             {
                 let entry = e.map_err(|err| Error::DefaultError(err.to_string()))?;
-                if entry.path().is_dir() {
-                    let f = File::open(entry.path()).map_err(Error::IOError)?;
-                    fs.push(f);
+                // a directory hashes as the path, length and content of its files in name
+                // order. A checksum stored for a directory by an older imagedrive differs,
+                // and fsck reports it
+                if entry.path().is_file() {
+                    fs.push(entry.path().to_path_buf());
                 }
             }
         } else {
             return Err(Error::BadPath(path.display().to_string()));
         }
-        Ok(HostItem { path, id, fs })
+        Ok(HostItem {
+            path,
+            id,
+            fs,
+            jobs: 1,
+            algorithms: vec![Algorithm::default()],
+            cache: None,
+        })
+    }
+
+    /// with_jobs read files of a directory on up to `jobs` threads while hashing
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// with_algorithms hash with each of `algorithms` in one pass, the first is of `hash`
+    pub fn with_algorithms(mut self, algorithms: &[Algorithm]) -> Self {
+        if !algorithms.is_empty() {
+            self.algorithms = algorithms.to_vec();
+        }
+        self
+    }
+
+    /// with_cache reuse checksums in `cache` while the item is unchanged
+    pub fn with_cache(mut self, cache: Option<&'a HashCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// checksums of the item as stored, one per algorithm
+    pub fn checksums(&self) -> Result<Vec<String>, Error> {
+        let cache = match self.cache {
+            None => return self.read_checksums(),
+            Some(cache) => cache,
+        };
+        let path = self.path.canonicalize().map_err(Error::IOError)?;
+        let stamp = hashcache::stamp(self.path, &self.fs).map_err(Error::IOError)?;
+        if let Some(sums) = cache.get(&path, &stamp, &self.algorithms) {
+            return Ok(sums);
+        }
+        let sums = self.read_checksums()?;
+        cache.put(&path, &stamp, &sums);
+        Ok(sums)
+    }
+
+    fn read_checksums(&self) -> Result<Vec<String>, Error> {
+        let mut hashers: Vec<Hasher> = self.algorithms.iter().map(|a| a.hasher()).collect();
+        let mut input = |data: &[u8]| {
+            for h in hashers.iter_mut() {
+                h.write_all(data).unwrap();
+            }
+        };
+        // each file of a directory is preceded by its relative path and length, so the
+        // same bytes split in other files or under other names hash differently
+        let headers = if self.path.is_dir() {
+            self.fs
+                .iter()
+                .map(|f| {
+                    let len = std::fs::metadata(f).map_err(Error::IOError)?.len();
+                    let name = f.strip_prefix(self.path).unwrap_or(f);
+                    Ok(format!("{}\0{}\0", name.display(), len).into_bytes())
+                })
+                .collect::<Result<Vec<_>, Error>>()?
+        } else {
+            vec![vec![]]
+        };
+        let mut i = 0;
+        while i < self.fs.len() {
+            // a run of small files is read ahead together, the hash is still over the files in order
+            let mut j = i;
+            while j < self.fs.len()
+                && j - i < self.jobs * 8
+                && std::fs::metadata(&self.fs[j])
+                    .map_err(Error::IOError)?
+                    .len()
+                    <= READ_AHEAD_MAX
+            {
+                j += 1;
+            }
+            if j == i {
+                input(&headers[i]);
+                let mut f = File::open(&self.fs[i]).map_err(Error::IOError)?;
+                let mut buf = vec![0; 64 * 1024];
+                loop {
+                    let n = f.read(&mut buf).map_err(Error::IOError)?;
+                    if n == 0 {
+                        break;
+                    }
+                    input(&buf[..n]);
+                }
+                i += 1;
+                continue;
+            }
+            let contents = crate::parallel::map(&self.fs[i..j], self.jobs, |p| read(p));
+            for (header, content) in headers[i..j].iter().zip(contents) {
+                input(header);
+                input(&content.map_err(Error::IOError)?);
+            }
+            i = j;
+        }
+        Ok(hashers.into_iter().map(|h| h.finish()).collect())
     }
 }
 
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let mut content = vec![];
+    File::open(path)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
 impl<'a> crate::Item for HostItem<'a> {
     fn hash(&mut self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        for f in &mut self.fs {
-            f.seek(SeekFrom::Start(0)).unwrap();
-            copy(f, &mut hasher).unwrap();
-        }
-        hasher.result().to_vec()
+        let checksums = self.checksums().unwrap();
+        hash::parse(&checksums[0]).unwrap().1
     }
 
     fn id(&self) -> &str {
@@ -71,3 +193,31 @@ impl<'a> crate::Item for HostItem<'a> {
         self.path
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dir_layout_is_hashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = |name: &str, files: &[(&str, &str)]| {
+            let root = dir.path().join(name);
+            for (path, content) in files {
+                let path = root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, content).unwrap();
+            }
+            HostItem::new(&root, None).unwrap().checksums().unwrap()
+        };
+        let one = layout("one", &[("x", "ab")]);
+        assert_ne!(one, layout("split", &[("y", "a"), ("z", "b")]));
+        assert_ne!(one, layout("renamed", &[("w", "ab")]));
+        assert_ne!(one, layout("nested", &[("d/x", "ab")]));
+        assert_eq!(one, layout("same", &[("x", "ab")]));
+        assert_ne!(
+            layout("empty_a", &[("a", "")]),
+            layout("empty_b", &[("b", "")])
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use crate::hash;
+use crate::Change;
+use std::collections::BTreeMap;
+
+/// Index maps `<entry>/<item>` to the checksum stored for it,
+/// `None` if the item has no checksum file
+pub type Index = BTreeMap<String, Option<String>>;
+
+/// path of the committed index inside the drive
+pub const COMMITTED_PATH: &str = "/.imagedrive/committed";
+
+/// LIST_CMD print every item under /data, directories with a trailing `/`,
+/// and every checksum line under /checksum/data
+pub const LIST_CMD: &str = "find /data -mindepth 2 -maxdepth 2 ! -type d 2>/dev/null; \
+                            find /data -mindepth 2 -maxdepth 2 -type d 2>/dev/null | sed 's|$|/|'; \
+                            grep -r . /checksum/data 2>/dev/null; true";
+
+/// Record is the checksum file of an item: the checksum on the first line,
+/// checksums of other algorithms on the next lines, then `key=value` attributes
+#[derive(Debug, Default, PartialEq)]
+pub struct Record {
+    pub checksum: Option<String>,
+    /// the same content hashed with other algorithms
+    pub others: Vec<String>,
+    /// original size
+    pub size: Option<u64>,
+    /// size after compression and encryption
+    pub stored: Option<u64>,
+    /// the item is a directory, known from the listing and not kept in the checksum file
+    pub dir: bool,
+}
+
+impl Record {
+    /// format the checksum file content
+    pub fn format(&self) -> String {
+        let mut s = format!(
+            "{}\n",
+            self.checksum.as_deref().unwrap_or("")
+        );
+        for sum in &self.others {
+            s.push_str(&format!("{}\n", sum));
+        }
+        if let Some(size) = self.size {
+            s.push_str(&format!("size={}\n", size));
+        }
+        if let Some(stored) = self.stored {
+            s.push_str(&format!("stored={}\n", stored));
+        }
+        s
+    }
+
+    fn add_line(&mut self, line: &str) {
+        if let Some(v) = strip(line, "size=") {
+            self.size = v.parse().ok();
+        } else if let Some(v) = strip(line, "stored=") {
+            self.stored = v.parse().ok();
+        } else if self.checksum.is_none() {
+            self.checksum = Some(hash::normalize(line));
+        } else if hash::parse(line).is_some() {
+            self.others.push(hash::normalize(line));
+        }
+    }
+
+    /// checksums of every algorithm, the first is `checksum`
+    pub fn checksums(&self) -> Vec<String> {
+        self.checksum.iter().chain(&self.others).cloned().collect()
+    }
+}
+
+/// same is true if `a` and `b` share a checksum, so their content is the same
+pub fn same(a: &[String], b: &[String]) -> bool {
+    a.iter().any(|sum| b.contains(sum))
+}
+
+/// parse_records build records of items from the output of `LIST_CMD`
+pub fn parse_records(out: &str) -> BTreeMap<String, Record> {
+    let mut items = BTreeMap::new();
+    let mut records: BTreeMap<String, Record> = BTreeMap::new();
+    for line in out.lines() {
+        let line = line.trim();
+        if let Some(rest) = strip(line, "/checksum/data/") {
+            if let Some(pos) = rest.find(':') {
+                let (key, value) = (&rest[..pos], rest[pos + 1..].trim());
+                records
+                    .entry(key.to_owned())
+                    .or_default()
+                    .add_line(value);
+            }
+        } else if let Some(key) = strip(line, "/data/") {
+            let (key, dir) = match key.strip_suffix('/') {
+                Some(key) => (key, true),
+                None => (key, false),
+            };
+            let record = Record {
+                dir,
+                ..Record::default()
+            };
+            items.insert(key.to_owned(), record);
+        }
+    }
+    for (key, record) in items.iter_mut() {
+        if let Some(r) = records.remove(key) {
+            *record = Record {
+                dir: record.dir,
+                ..r
+            };
+        }
+    }
+    items
+}
+
+/// parse_orphans return keys of checksum files in the output of `LIST_CMD` without an item
+pub fn parse_orphans(out: &str) -> Vec<String> {
+    let items = parse_records(out);
+    let mut orphans: Vec<String> = out
+        .lines()
+        .filter_map(|line| strip(line.trim(), "/checksum/data/"))
+        .filter_map(|rest| rest.find(':').map(|pos| &rest[..pos]))
+        .filter(|key| !items.contains_key(*key))
+        .map(|key| key.to_owned())
+        .collect();
+    orphans.dedup();
+    orphans
+}
+
+/// parse_listing build an Index from the output of `LIST_CMD`
+pub fn parse_listing(out: &str) -> Index {
+    parse_records(out)
+        .into_iter()
+        .map(|(key, record)| (key, record.checksum))
+        .collect()
+}
+
+/// parse_committed parse the committed index file, one `<checksum> <entry>/<item>` per line
+pub fn parse_committed(content: &str) -> Index {
+    let mut index = Index::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if let Some(pos) = line.find(' ') {
+            let (sum, key) = (&line[..pos], &line[pos + 1..]);
+            let sum = if sum == "-" {
+                None
+            } else {
+                Some(hash::normalize(sum))
+            };
+            index.insert(key.to_owned(), sum);
+        }
+    }
+    index
+}
+
+/// format_committed is the reverse of `parse_committed`
+pub fn format_committed(index: &Index) -> String {
+    let mut s = String::new();
+    for (key, sum) in index {
+        s.push_str(sum.as_ref().map(|s| s.as_str()).unwrap_or("-"));
+        s.push(' ');
+        s.push_str(key);
+        s.push('\n');
+    }
+    s
+}
+
+/// diff compare `committed` with `current`. Items without checksum are compared by presence only,
+/// their content is unknown
+pub fn diff(committed: &Index, current: &Index) -> Vec<Change> {
+    let mut changes = vec![];
+    for (key, sum) in current {
+        match committed.get(key) {
+            None => changes.push(Change::Added(key.clone())),
+            Some(old) => {
+                if old != sum {
+                    changes.push(Change::Modified(key.clone()));
+                }
+            }
+        }
+    }
+    for key in committed.keys() {
+        if !current.contains_key(key) {
+            changes.push(Change::Deleted(key.clone()));
+        }
+    }
+    changes
+}
+
+fn strip<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    s.strip_prefix(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listing_joins_checksums() {
+        let out = "/data/e1/a.txt\n/data/e1/b\n/checksum/data/e1/a.txt:abcd\n";
+        let index = parse_listing(out);
+        assert_eq!(index.len(), 2);
+        // checksums of old imagedrive are bare sha256
+        assert_eq!(index["e1/a.txt"], Some("sha256:abcd".to_owned()));
+        assert_eq!(index["e1/b"], None);
+    }
+
+    #[test]
+    fn parse_records_with_sizes() {
+        let out = "/data/e1/a\n/checksum/data/e1/a:abcd\n/checksum/data/e1/a:size=10\n\
+                   /checksum/data/e1/a:stored=4\n";
+        let records = parse_records(out);
+        assert_eq!(
+            records["e1/a"],
+            Record {
+                checksum: Some("sha256:abcd".to_owned()),
+                others: vec![],
+                size: Some(10),
+                stored: Some(4),
+                dir: false,
+            }
+        );
+        assert_eq!(records["e1/a"].format(), "sha256:abcd\nsize=10\nstored=4\n");
+    }
+
+    #[test]
+    fn parse_records_of_directories() {
+        let out = "/data/e1/a\n/data/e1/d/\n/checksum/data/e1/d:abcd\n";
+        let records = parse_records(out);
+        assert_eq!(records.len(), 2);
+        assert!(!records["e1/a"].dir);
+        assert!(records["e1/d"].dir);
+        assert_eq!(records["e1/d"].checksum, Some("sha256:abcd".to_owned()));
+    }
+
+    #[test]
+    fn parse_records_with_several_checksums() {
+        let out = "/data/e1/a\n/checksum/data/e1/a:blake3:01\n/checksum/data/e1/a:sha256:02\n\
+                   /checksum/data/e1/a:size=10\n";
+        let record = parse_records(out).remove("e1/a").unwrap();
+        assert_eq!(record.checksums(), vec!["blake3:01", "sha256:02"]);
+        assert_eq!(record.format(), "blake3:01\nsha256:02\nsize=10\n");
+        assert!(same(&record.checksums(), &["sha256:02".to_owned()]));
+        assert!(!same(&record.checksums(), &["blake3:02".to_owned()]));
+    }
+
+    #[test]
+    fn orphaned_checksums() {
+        let out = "/data/e1/a\n/checksum/data/e1/a:01\n/checksum/data/e1/gone:02\n\
+                   /checksum/data/e1/gone:size=3\n";
+        assert_eq!(parse_orphans(out), vec!["e1/gone".to_owned()]);
+    }
+
+    #[test]
+    fn committed_roundtrip() {
+        let out = "/data/e1/a b\n/data/e2/c\n/checksum/data/e1/a b:01\n";
+        let index = parse_listing(out);
+        assert_eq!(parse_committed(&format_committed(&index)), index);
+    }
+
+    #[test]
+    fn diff_changes() {
+        let committed = parse_committed("01 e/a\n02 e/b\n03 e/c\n");
+        let current = parse_committed("01 e/a\nff e/b\n04 e/d\n");
+        assert_eq!(
+            diff(&committed, &current),
+            vec![
+                Change::Modified("e/b".to_owned()),
+                Change::Added("e/d".to_owned()),
+                Change::Deleted("e/c".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_without_checksums() {
+        let committed = parse_committed("- e/a\n01 e/b\n");
+        let current = parse_committed("- e/a\n- e/b\n");
+        assert_eq!(
+            diff(&committed, &current),
+            vec![Change::Modified("e/b".to_owned())]
+        );
+    }
+}
@@ -1,11 +1,28 @@
+extern crate aes_gcm;
+extern crate blake3;
 extern crate dockerclient;
+extern crate fastcdc;
+extern crate flate2;
 extern crate hex;
+extern crate rusqlite;
 extern crate sha2;
+extern crate tempfile;
 extern crate walkdir;
+extern crate zstd;
 
+pub mod chunk;
+pub mod cipher;
+pub mod codec;
 mod containeritem;
 pub mod docker;
+pub mod hash;
+pub mod hashcache;
+mod history;
 mod hostitem;
+mod index;
+pub mod metacache;
+mod parallel;
+mod trash;
 mod utils;
 pub trait Item {
     /// Hash compute item's hash value
@@ -16,15 +33,114 @@ pub trait Item {
     fn srcpath(&self) -> &std::path::Path;
 }
 
-/// compare_items compare 2 items, true if same
-fn compare_items<T1: Item, T2: Item + ?Sized>(i1: &mut T1, i2: &mut T2) -> bool {
-    i1.hash() == i2.hash()
-}
-
 #[derive(Debug)]
 pub enum AddResult {
     ExistedItem(String),
     Succ,
+    /// an item of the same name is replaced, with checksums of the old and new content
+    Replaced {
+        item: String,
+        old: String,
+        new: String,
+    },
+    /// an item of the same name exists, so the item is added under this name
+    Renamed(String),
+}
+
+/// AddResults is the result of each item of `DB::add_many`, with its path
+pub type AddResults<E> = Vec<(std::path::PathBuf, Result<AddResult, E>)>;
+
+/// Conflict tell `DB::add` what to do when the entry has an item of the same name
+/// and different content
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Conflict {
+    /// replace the existing item
+    Overwrite,
+    /// keep the existing item and fail
+    #[default]
+    NoClobber,
+    /// add the item under a free name, like `name (1).txt`
+    Rename,
+}
+
+/// ItemInfo is an item with metadata saved when it is added
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemInfo {
+    pub name: String,
+    pub checksum: Option<String>,
+    /// original size in bytes, None for items added by old imagedrive
+    pub size: Option<u64>,
+    /// size in the drive after compression and encryption
+    pub stored: Option<u64>,
+    /// a directory put as a whole, which `cat` can't read.
+    /// Unknown in the meta cache, where it is always false
+    pub dir: bool,
+}
+
+/// Change is an item which differs between localDB and the last commit,
+/// items are named `<entry>/<item>`
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added(String),
+    Modified(String),
+    Deleted(String),
+}
+
+/// RemoteState compare local committed image with remote image
+#[derive(Debug, PartialEq)]
+pub enum RemoteState {
+    UpToDate,
+    Differ,
+    /// remote unreachable or local image never pushed
+    Unknown,
+}
+
+/// Revision is a sync which committed changes to the drive image
+#[derive(Debug, PartialEq, Clone)]
+pub struct Revision {
+    pub rev: u32,
+    /// unix timestamp of the sync
+    pub time: u64,
+    pub message: String,
+    /// number of items added, modified and deleted by this revision
+    pub added: usize,
+    pub modified: usize,
+    pub deleted: usize,
+}
+
+/// Snapshot is a named tag of the drive image, see `DB::snapshot_create`
+#[derive(Debug)]
+pub struct Snapshot {
+    pub name: String,
+    /// creation time of the snapshot image, as docker prints it
+    pub created: String,
+}
+
+/// TrashItem is an item deleted by `DB::delete`, which can still be restored
+#[derive(Debug)]
+pub struct TrashItem {
+    pub entry: String,
+    pub item: String,
+    /// unix timestamp of the deletion
+    pub deleted: u64,
+}
+
+/// MirrorReport is the result of `DB::mirror_push` and `DB::mirror_pull`, values are item names
+#[derive(Debug, Default)]
+pub struct MirrorReport {
+    /// items new or changed, which are copied
+    pub transferred: Vec<String>,
+    /// extra items deleted from the destination
+    pub deleted: Vec<String>,
+    /// number of items which are the same on both sides
+    pub unchanged: usize,
+}
+
+#[derive(Debug)]
+pub struct Status {
+    /// changes not committed yet
+    pub changes: Vec<Change>,
+    pub remote: RemoteState,
 }
 
 pub trait DB<E>
@@ -35,20 +151,79 @@ where
     fn entries(&self) -> Result<Vec<String>, E>;
     /// items list items under `entry`
     fn items(&self, entry: &str) -> Result<Vec<String>, E>;
-    /// add `item` to DB under `entry`
+    /// item_infos list items under `entry` with their metadata
+    fn item_infos(&self, entry: &str) -> Result<Vec<ItemInfo>, E>;
+    /// add `item` to DB under `entry`, `conflict` decides what happens to an item
+    /// of the same name and different content
     fn add(
         &self,
         entry: &str,
         itempath: &std::path::Path,
         rename: Option<&str>,
+        conflict: Conflict,
     ) -> Result<AddResult, E>;
-    /// delete item from DB, which is located by entry and reference
+    /// add_many add files or dirs at `itempaths` under `entry` in one go,
+    /// the result of each one is returned with its path
+    fn add_many(
+        &self,
+        entry: &str,
+        itempaths: &[&std::path::Path],
+        conflict: Conflict,
+    ) -> Result<AddResults<E>, E>;
+    /// mirror_push make `entry` the same as host dir `dir`: new and changed files are added,
+    /// and items which are not in `dir` are deleted if `delete`
+    fn mirror_push(
+        &self,
+        dir: &std::path::Path,
+        entry: &str,
+        delete: bool,
+    ) -> Result<MirrorReport, E>;
+    /// mirror_pull make host dir `dir` the same as `entry`, the reverse of `mirror_push`
+    fn mirror_pull(
+        &self,
+        entry: &str,
+        dir: &std::path::Path,
+        delete: bool,
+    ) -> Result<MirrorReport, E>;
+    /// delete item from DB, which is located by entry and reference.
+    /// Deleted items are moved to the trash
     fn delete(&self, entry: &str, item: Option<&str>) -> Result<(), E>;
+    /// rename `item` of `entry` to `new_name`, failing if an item of that name exists
+    fn rename(&self, entry: &str, item: &str, new_name: &str) -> Result<(), E>;
     /// export_to_dir export `entry` to `dir`
     fn export_to_dir(&self, dir: &std::path::Path, entry: &str) -> Result<(), E>;
-    /// sync local DB to remote DB
+    /// cat write content of file `item` under `entry` to `out`
+    fn cat(&self, entry: &str, item: &str, out: &mut dyn std::io::Write) -> Result<(), E>;
+    /// sync local DB to remote DB, changes are committed as a new revision with `message`
     /// or sync remote DB to local DB if localDB not exists
-    fn sync(&self) -> Result<(), E>;
+    fn sync(&self, message: Option<&str>) -> Result<(), E>;
     /// sync remote DB to local DB even localDB exists, so it will overwrite localDB
     fn sync_from_remote(&self) -> Result<(), E>;
+    /// status list changes not synced yet, like `git status`
+    fn status(&self) -> Result<Status, E>;
+    /// log list revisions, oldest first
+    fn log(&self) -> Result<Vec<Revision>, E>;
+    /// show revision `rev` and changes made by it
+    fn show(&self, rev: u32) -> Result<(Revision, Vec<Change>), E>;
+    /// checkout make localDB the same as revision `rev`, it is committed as a new revision
+    /// by the next sync. Refuse to drop unsynced changes unless `force`
+    fn checkout(&self, rev: u32, force: bool) -> Result<(), E>;
+    /// revert undo changes made by revision `rev` in localDB, return changes made by reverting.
+    /// Refuse to drop unsynced changes unless `force`
+    fn revert(&self, rev: u32, force: bool) -> Result<Vec<Change>, E>;
+    /// snapshot_create tag the last synced revision as snapshot `name`, and push it to remote
+    fn snapshot_create(&self, name: &str) -> Result<(), E>;
+    /// snapshots list snapshots on this host. The registry isn't asked, so snapshots
+    /// pushed from other hosts are missing until they are restored here
+    fn snapshots(&self) -> Result<Vec<Snapshot>, E>;
+    /// snapshot_restore make localDB the same as snapshot `name`, pull it if not on this host.
+    /// Refuse to drop unsynced changes unless `force`
+    fn snapshot_restore(&self, name: &str, force: bool) -> Result<(), E>;
+    /// trash list deleted items, most recently deleted first
+    fn trash(&self) -> Result<Vec<TrashItem>, E>;
+    /// trash_restore move the most recently deleted `item` of `entry` back from the trash
+    fn trash_restore(&self, entry: &str, item: &str) -> Result<(), E>;
+    /// trash_empty remove items deleted more than `older_than` seconds ago from the trash,
+    /// or all of them if None. Return the number of items removed
+    fn trash_empty(&self, older_than: Option<u64>) -> Result<usize, E>;
 }
@@ -0,0 +1,227 @@
+use crate::index::Record;
+use crate::Change;
+use rusqlite::{params, Connection};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Stamp tells how fresh the cached index of a drive is
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stamp {
+    /// id of the local image when the index was cached
+    pub digest: String,
+    /// unix timestamp of the refresh
+    pub refreshed: u64,
+}
+
+/// Index is what is cached of a drive, names as stored in the drive
+#[derive(Debug, Default, PartialEq)]
+pub struct Index {
+    pub entries: Vec<String>,
+    /// `<entry>/<item>` to its checksum record
+    pub records: BTreeMap<String, Record>,
+    /// changes not synced
+    pub changes: Vec<Change>,
+}
+
+/// MetaCache is a local sqlite copy of the index of drives, so they can be
+/// listed without docker. Names are kept as stored, so encrypted names stay encrypted
+pub struct MetaCache {
+    conn: Mutex<Connection>,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS drives (
+    image TEXT PRIMARY KEY,
+    digest TEXT NOT NULL,
+    refreshed INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS entries (
+    image TEXT NOT NULL,
+    entry TEXT NOT NULL,
+    PRIMARY KEY (image, entry)
+);
+CREATE TABLE IF NOT EXISTS items (
+    image TEXT NOT NULL,
+    entry TEXT NOT NULL,
+    item TEXT NOT NULL,
+    checksum TEXT,
+    others TEXT NOT NULL,
+    size INTEGER,
+    stored INTEGER,
+    PRIMARY KEY (image, entry, item)
+);
+CREATE TABLE IF NOT EXISTS changes (
+    image TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    key TEXT NOT NULL
+);
+";
+
+impl MetaCache {
+    pub fn open(path: &Path) -> rusqlite::Result<MetaCache> {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(MetaCache {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// store `index` of drive `image` as of `stamp`, replacing what was cached
+    pub fn store(&self, image: &str, stamp: &Stamp, index: &Index) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for table in &["drives", "entries", "items", "changes"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE image = ?1", table),
+                params![image],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO drives (image, digest, refreshed) VALUES (?1, ?2, ?3)",
+            params![image, stamp.digest, stamp.refreshed as i64],
+        )?;
+        for entry in &index.entries {
+            tx.execute(
+                "INSERT INTO entries (image, entry) VALUES (?1, ?2)",
+                params![image, entry],
+            )?;
+        }
+        for (key, record) in &index.records {
+            let (entry, item) = split_key(key);
+            tx.execute(
+                "INSERT INTO items (image, entry, item, checksum, others, size, stored) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    image,
+                    entry,
+                    item,
+                    record.checksum,
+                    record.others.join(","),
+                    record.size.map(|s| s as i64),
+                    record.stored.map(|s| s as i64)
+                ],
+            )?;
+        }
+        for change in &index.changes {
+            let (kind, key) = match change {
+                Change::Added(key) => ("added", key),
+                Change::Modified(key) => ("modified", key),
+                Change::Deleted(key) => ("deleted", key),
+            };
+            tx.execute(
+                "INSERT INTO changes (image, kind, key) VALUES (?1, ?2, ?3)",
+                params![image, kind, key],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// stamp of the cached index of `image`, None if it was never cached
+    pub fn stamp(&self, image: &str) -> rusqlite::Result<Option<Stamp>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT digest, refreshed FROM drives WHERE image = ?1")?;
+        let mut rows = stmt.query_map(params![image], |row| {
+            Ok(Stamp {
+                digest: row.get(0)?,
+                refreshed: row.get::<_, i64>(1)? as u64,
+            })
+        })?;
+        rows.next().transpose()
+    }
+
+    /// load the cached index of `image`
+    pub fn load(&self, image: &str) -> rusqlite::Result<Index> {
+        let conn = self.conn.lock().unwrap();
+        let mut index = Index::default();
+        let mut stmt = conn.prepare("SELECT entry FROM entries WHERE image = ?1 ORDER BY entry")?;
+        for entry in stmt.query_map(params![image], |row| row.get(0))? {
+            index.entries.push(entry?);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT entry, item, checksum, others, size, stored FROM items WHERE image = ?1",
+        )?;
+        let rows = stmt.query_map(params![image], |row| {
+            let entry: String = row.get(0)?;
+            let item: String = row.get(1)?;
+            let others: String = row.get(3)?;
+            let record = Record {
+                checksum: row.get(2)?,
+                others: others
+                    .split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_owned())
+                    .collect(),
+                size: row.get::<_, Option<i64>>(4)?.map(|s| s as u64),
+                stored: row.get::<_, Option<i64>>(5)?.map(|s| s as u64),
+                dir: false,
+            };
+            Ok((format!("{}/{}", entry, item), record))
+        })?;
+        for row in rows {
+            let (key, record) = row?;
+            index.records.insert(key, record);
+        }
+        let mut stmt = conn.prepare("SELECT kind, key FROM changes WHERE image = ?1")?;
+        let rows = stmt.query_map(params![image], |row| {
+            let kind: String = row.get(0)?;
+            let key: String = row.get(1)?;
+            Ok(match kind.as_str() {
+                "added" => Change::Added(key),
+                "deleted" => Change::Deleted(key),
+                _ => Change::Modified(key),
+            })
+        })?;
+        for change in rows {
+            index.changes.push(change?);
+        }
+        Ok(index)
+    }
+}
+
+fn split_key(key: &str) -> (&str, &str) {
+    match key.find('/') {
+        Some(pos) => (&key[..pos], &key[pos + 1..]),
+        None => (key, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = MetaCache::open(&dir.path().join("cache/index.db")).unwrap();
+        assert_eq!(cache.stamp("drive:latest").unwrap(), None);
+        let mut index = Index {
+            entries: vec!["e1".to_owned(), "e2".to_owned()],
+            ..Default::default()
+        };
+        index.records.insert(
+            "e1/a b".to_owned(),
+            Record {
+                checksum: Some("sha256:01".to_owned()),
+                others: vec!["blake3:02".to_owned()],
+                size: Some(10),
+                stored: None,
+                dir: false,
+            },
+        );
+        index.changes = vec![Change::Added("e1/a b".to_owned())];
+        let stamp = Stamp {
+            digest: "sha256:ff".to_owned(),
+            refreshed: 1549000000,
+        };
+        cache.store("drive:latest", &stamp, &index).unwrap();
+        cache
+            .store("other:latest", &stamp, &Index::default())
+            .unwrap();
+        assert_eq!(cache.stamp("drive:latest").unwrap(), Some(stamp));
+        assert_eq!(cache.load("drive:latest").unwrap(), index);
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+
+/// deleted items are moved to `TRASH_DIR/<time>-<nanos>/data/<entry>/<item>`,
+/// their checksum files to `TRASH_DIR/<time>-<nanos>/checksum/data/<entry>/<item>`
+pub const TRASH_DIR: &str = "/trash";
+
+/// LIST_CMD print every item in the trash
+pub const LIST_CMD: &str = "find /trash -mindepth 4 -maxdepth 4 2>/dev/null; true";
+
+/// Deleted is an item in the trash, named as stored in the drive
+#[derive(Debug, PartialEq)]
+pub struct Deleted {
+    /// name of the trash dir holding the item
+    pub dir: String,
+    /// unix timestamp of the deletion
+    pub time: u64,
+    pub entry: String,
+    pub item: String,
+}
+
+impl Deleted {
+    pub fn data_path(&self) -> PathBuf {
+        Path::new(TRASH_DIR)
+            .join(&self.dir)
+            .join("data")
+            .join(&self.entry)
+            .join(&self.item)
+    }
+
+    pub fn checksum_path(&self) -> PathBuf {
+        Path::new(TRASH_DIR)
+            .join(&self.dir)
+            .join("checksum/data")
+            .join(&self.entry)
+            .join(&self.item)
+    }
+}
+
+/// dir_name is the name of a trash dir created at `time`
+pub fn dir_name(time: std::time::Duration) -> String {
+    format!("{}-{:09}", time.as_secs(), time.subsec_nanos())
+}
+
+/// dir_time is the unix timestamp of trash dir `name`
+pub fn dir_time(name: &str) -> Option<u64> {
+    name.split('-').next().and_then(|t| t.parse().ok())
+}
+
+/// parse_listing parse the output of `LIST_CMD`, most recently deleted first
+pub fn parse_listing(out: &str) -> Vec<Deleted> {
+    let mut deleted = vec![];
+    for line in out.lines() {
+        let parts: Vec<&str> = line.trim().split('/').collect();
+        // "", "trash", dir, "data", entry, item
+        if parts.len() != 6 || parts[1] != "trash" || parts[3] != "data" {
+            continue;
+        }
+        if let Some(time) = dir_time(parts[2]) {
+            deleted.push(Deleted {
+                dir: parts[2].to_owned(),
+                time,
+                entry: parts[4].to_owned(),
+                item: parts[5].to_owned(),
+            });
+        }
+    }
+    deleted.sort_by(|a, b| b.dir.cmp(&a.dir));
+    deleted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_trash_listing() {
+        let out = "/trash/1549000000-000000001/data/e1/a\n\
+                   /trash/1549000000-000000001/checksum/data/e1\n\
+                   /trash/1549000500-000000000/data/e1/a\n";
+        let deleted = parse_listing(out);
+        assert_eq!(deleted.len(), 2);
+        assert_eq!(deleted[0].time, 1549000500);
+        assert_eq!(deleted[0].entry, "e1");
+        assert_eq!(deleted[0].item, "a");
+        assert_eq!(
+            deleted[1].checksum_path(),
+            Path::new("/trash/1549000000-000000001/checksum/data/e1/a")
+        );
+    }
+}
@@ -1,27 +1,380 @@
 use crate::*;
+use std::collections::HashMap;
+use std::ops::Deref;
 
+/// every container created by imagedrive has this label, its value is imagedrive's version
+pub const LABEL: &str = "imagedrive";
+/// ROLE_LABEL tells `ROLE_WORK` container from `ROLE_SCRATCH` containers
+pub const ROLE_LABEL: &str = "imagedrive.role";
+/// PID_LABEL is the pid of process which created the scratch container
+pub const PID_LABEL: &str = "imagedrive.pid";
+/// HOST_LABEL is the hostname of the host of that process, its pid means nothing elsewhere
+pub const HOST_LABEL: &str = "imagedrive.host";
+/// scratch containers of other hosts are orphans once older than ORPHAN_AGE seconds,
+/// whether their process runs can't be told from here
+const ORPHAN_AGE: i64 = 24 * 60 * 60;
+/// container holding localDB
+pub const ROLE_WORK: &str = "work";
+/// throwaway container, removed when its `ScratchContainer` drops
+pub const ROLE_SCRATCH: &str = "scratch";
+
+/// DRIVE_LABEL is `drive_name` of the image the work container belongs to
+pub const DRIVE_LABEL: &str = "imagedrive.drive";
+/// IMAGE_LABEL is the normalized image reference of the work container
+pub const IMAGE_LABEL: &str = "imagedrive.image";
+
+/// normalize add the default `latest` tag to `image` if it has neither tag nor digest
+pub fn normalize(image: &str) -> String {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    if name.contains(':') || name.contains('@') {
+        image.to_owned()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+/// free_name is `name` with a number added before its extension, like `a (1).txt`,
+/// the first one which is not `taken`
+pub fn free_name<F: Fn(&str) -> bool>(name: &str, taken: F) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(pos) if pos > 0 => (&name[..pos], &name[pos..]),
+        _ => (name, ""),
+    };
+    let mut n = 1;
+    loop {
+        let candidate = format!("{} ({}){}", stem, n, ext);
+        if !taken(&candidate) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// quote `word`, a name or a path, for the container's sh, so it stays one word
+/// whatever it holds
+pub fn quote<S: AsRef<std::ffi::OsStr>>(word: S) -> String {
+    format!(
+        "'{}'",
+        word.as_ref().to_string_lossy().replace('\'', "'\\''")
+    )
+}
+
+/// matches is true if `name` matches glob `pattern` with `*` and `?`,
+/// a pattern without them matches names containing it
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        return name.contains(pattern);
+    }
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut i, mut j) = (0, 0);
+    // position of the last `*` in pattern, and of name when it was met
+    let mut star = None;
+    while j < n.len() {
+        if i < p.len() && (p[i] == '?' || p[i] == n[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == '*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+    p[i..].iter().all(|&c| c == '*')
+}
+
+/// repository split `image` into repository and tag, tag is `latest` if omitted.
+/// Images referenced by digest have no tag
+pub fn repository(image: &str) -> (&str, Option<&str>) {
+    let name_start = image.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let name = &image[name_start..];
+    if name.contains('@') {
+        return (image, None);
+    }
+    match name.rfind(':') {
+        Some(i) => (&image[..name_start + i], Some(&name[i + 1..])),
+        None => (image, Some("latest")),
+    }
+}
+
+/// drive_name is `image` normalized and made usable as container name
+pub fn drive_name(image: &str) -> String {
+    normalize(image)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+/// get_or_run return the work container of `image`, create and start it if not exists.
+/// The work container is named `imagedrive-<drive_name>` and found by `DRIVE_LABEL`;
+/// a container with the drive label but another image is refused.
+/// A stopped work container (e.g. after reboot) is started again instead of replaced,
+/// since it holds everything not synced yet.
 pub fn get_or_run(
     cli: &dockerclient::DockerClient,
     image: &str,
-) -> Result<dockerclient::Container, dockerclient::Error> {
-    let cs = cli.ps(false)?;
-    for c in cs {
-        if c.image.split(":").collect::<Vec<&str>>()[0]
-            == image.split(":").collect::<Vec<&str>>()[0]
-        {
-            return Ok(c);
+) -> Result<dockerclient::Container, docker::Error> {
+    let c = find_work_container(cli, image)?;
+    if !c.status.starts_with("Up") {
+        if c.status != "Created" {
+            println!("start stopped work container: {}", c.id);
         }
+        cli.start(&c.id).map_err(docker::Error::DockerError)?;
     }
-    // not found
-    let c = cli.create(image)?;
-    cli.start(&c.id)?;
     Ok(c)
 }
-pub fn run(
+
+/// running_work_container is the work container of `image` if it is running,
+/// it is never created or started
+pub fn running_work_container(
+    cli: &dockerclient::DockerClient,
+    image: &str,
+) -> Result<Option<dockerclient::Container>, docker::Error> {
+    Ok(existing_work_container(cli, image)?.filter(|c| c.status.starts_with("Up")))
+}
+
+fn find_work_container(
+    cli: &dockerclient::DockerClient,
+    image: &str,
+) -> Result<dockerclient::Container, docker::Error> {
+    if let Some(c) = existing_work_container(cli, image)? {
+        return Ok(c);
+    }
+    // not found
+    let image = normalize(image);
+    let drive = drive_name(&image);
+    let name = format!("imagedrive-{}", drive);
+    let mut labels = HashMap::new();
+    labels.insert(LABEL, env!("CARGO_PKG_VERSION"));
+    labels.insert(ROLE_LABEL, ROLE_WORK);
+    labels.insert(DRIVE_LABEL, &drive);
+    labels.insert(IMAGE_LABEL, &image);
+    // come back with the docker daemon, unless user stopped it
+    cli.create_with_labels(&image, Some(&name), &labels, Some("unless-stopped"))
+        .map_err(docker::Error::DockerError)
+}
+
+/// existing_work_container find the work container of `image`, None if there is none
+fn existing_work_container(
     cli: &dockerclient::DockerClient,
     image: &str,
-) -> Result<dockerclient::Container, dockerclient::Error> {
-    let c = cli.create(image)?;
+) -> Result<Option<dockerclient::Container>, docker::Error> {
+    let image = normalize(image);
+    let drive = drive_name(&image);
+    let cs = cli
+        .ps_with_label(true, &format!("{}={}", DRIVE_LABEL, drive))
+        .map_err(docker::Error::DockerError)?;
+    for c in cs {
+        if c.labels.get(ROLE_LABEL).map(|r| r.as_str()) != Some(ROLE_WORK) {
+            continue;
+        }
+        if c.labels.get(IMAGE_LABEL) != Some(&image) {
+            return Err(docker::Error::ForeignContainer(format!(
+                "container {} belongs to image {:?}, not {}",
+                c.id,
+                c.labels.get(IMAGE_LABEL),
+                image
+            )));
+        }
+        return Ok(Some(c));
+    }
+    // work container created before labels existed, keep using it so its data is not lost,
+    // prefer a running one, then the newest one
+    Ok(cli
+        .ps(true)
+        .map_err(docker::Error::DockerError)?
+        .into_iter()
+        .filter(|c| !c.labels.contains_key(LABEL) && normalize(&c.image) == image)
+        .max_by_key(|c| (c.status.starts_with("Up"), c.created)))
+}
+
+/// ScratchContainer is a running throwaway container, removed on drop
+pub struct ScratchContainer<'a> {
+    cli: &'a dockerclient::DockerClient,
+    container: dockerclient::Container,
+}
+
+impl<'a> Deref for ScratchContainer<'a> {
+    type Target = dockerclient::Container;
+    fn deref(&self) -> &dockerclient::Container {
+        &self.container
+    }
+}
+
+impl<'a> Drop for ScratchContainer<'a> {
+    fn drop(&mut self) {
+        let _ = self.cli.remove(&self.container.id);
+    }
+}
+
+/// run start a scratch container of `image`,
+/// containers left behind by killed processes are found by `orphans`
+pub fn run<'a>(
+    cli: &'a dockerclient::DockerClient,
+    image: &str,
+) -> Result<ScratchContainer<'a>, dockerclient::Error> {
+    let pid = std::process::id().to_string();
+    let host = hostname();
+    let mut labels = HashMap::new();
+    labels.insert(LABEL, env!("CARGO_PKG_VERSION"));
+    labels.insert(ROLE_LABEL, ROLE_SCRATCH);
+    labels.insert(PID_LABEL, &pid);
+    labels.insert(HOST_LABEL, &host);
+    let container = cli.create_with_labels(image, None, &labels, None)?;
+    // guard before start, so a failed start still removes it
+    let c = ScratchContainer { cli, container };
     cli.start(&c.id)?;
     Ok(c)
 }
+
+/// orphans list scratch containers whose creator process is gone
+pub fn orphans(
+    cli: &dockerclient::DockerClient,
+) -> Result<Vec<dockerclient::Container>, dockerclient::Error> {
+    let cs = cli.ps_with_label(true, &format!("{}={}", ROLE_LABEL, ROLE_SCRATCH))?;
+    let host = hostname();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    Ok(cs
+        .into_iter()
+        .filter(|c| is_orphan(c, &host, now))
+        .collect())
+}
+
+/// is_orphan tell if scratch container `c` is left behind. Its process is looked up only
+/// if it was created on `host`, else it is an orphan once older than `ORPHAN_AGE`
+fn is_orphan(c: &dockerclient::Container, host: &str, now: i64) -> bool {
+    match (c.labels.get(PID_LABEL), c.labels.get(HOST_LABEL)) {
+        (None, _) => true,
+        (Some(pid), Some(h)) if h == host => !process_alive(pid),
+        // another host, or created before the host was recorded
+        _ => now - c.created > ORPHAN_AGE,
+    }
+}
+
+/// hostname of this host, as the kernel has it, empty if unknown
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| {
+            std::process::Command::new("hostname")
+                .output()
+                .ok()
+                .map(|out| String::from_utf8_lossy(&out.stdout).to_string())
+        })
+        .map(|name| name.trim().to_owned())
+        .unwrap_or_default()
+}
+
+/// process_alive tell if process `pid` of this host runs. A process of another user runs
+/// too, though `kill -0` fails on it as not permitted
+fn process_alive(pid: &str) -> bool {
+    if pid.is_empty() || !pid.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    if std::path::Path::new("/proc/self").exists() {
+        return std::path::Path::new("/proc").join(pid).exists();
+    }
+    std::process::Command::new("kill")
+        .args(["-0", pid])
+        .output()
+        .map(|r| r.status.success() || String::from_utf8_lossy(&r.stderr).contains("not permitted"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_adds_latest() {
+        assert_eq!(normalize("busybox"), "busybox:latest");
+        assert_eq!(normalize("busybox:1.30"), "busybox:1.30");
+        assert_eq!(normalize("localhost:5000/drive"), "localhost:5000/drive:latest");
+        assert_eq!(normalize("drive@sha256:ab"), "drive@sha256:ab");
+    }
+
+    #[test]
+    fn repository_splits_tag() {
+        assert_eq!(repository("busybox"), ("busybox", Some("latest")));
+        assert_eq!(
+            repository("localhost:5000/drive:v1"),
+            ("localhost:5000/drive", Some("v1"))
+        );
+        assert_eq!(repository("drive@sha256:ab"), ("drive@sha256:ab", None));
+    }
+
+    #[test]
+    fn free_name_keeps_extension() {
+        assert_eq!(free_name("a.txt", |_| false), "a (1).txt");
+        assert_eq!(free_name("a.txt", |n| n == "a (1).txt"), "a (2).txt");
+        assert_eq!(free_name(".profile", |_| false), ".profile (1)");
+    }
+
+    #[test]
+    fn drive_name_is_container_name() {
+        assert_eq!(drive_name("localhost:5000/a/drive"), "localhost-5000-a-drive-latest");
+    }
+
+    #[test]
+    fn quote_is_one_word() {
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote(std::path::Path::new("/data/$(x)")), "'/data/$(x)'");
+    }
+
+    #[test]
+    fn orphans_of_other_hosts_age_out() {
+        let scratch = |labels: &[(&str, &str)], created: i64| dockerclient::Container {
+            id: "c".to_owned(),
+            names: vec![],
+            image: "drive:latest".to_owned(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            status: "Up".to_owned(),
+            created,
+        };
+        let pid = std::process::id().to_string();
+        let now = 10 * ORPHAN_AGE;
+        let here = scratch(&[(PID_LABEL, &pid), (HOST_LABEL, "h1")], 0);
+        assert!(!is_orphan(&here, "h1", now));
+        let elsewhere = scratch(&[(PID_LABEL, &pid), (HOST_LABEL, "h2")], now - 60);
+        assert!(!is_orphan(&elsewhere, "h1", now));
+        let old = scratch(
+            &[(PID_LABEL, &pid), (HOST_LABEL, "h2")],
+            now - 2 * ORPHAN_AGE,
+        );
+        assert!(is_orphan(&old, "h1", now));
+        assert!(!is_orphan(&scratch(&[(PID_LABEL, "1")], now), "h1", now));
+        assert!(is_orphan(&scratch(&[], now), "h1", now));
+    }
+
+    #[test]
+    fn glob_matches() {
+        assert!(matches("rep", "report.pdf"));
+        assert!(matches("*.pdf", "report.pdf"));
+        assert!(matches("r?p*t.*", "report.pdf"));
+        assert!(!matches("*.pdf", "report.pdf.txt"));
+        assert!(!matches("a?", "a"));
+    }
+
+    #[test]
+    fn processes_of_any_user_are_alive() {
+        assert!(process_alive(&std::process::id().to_string()));
+        // init runs as root, so `kill -0` isn't permitted on it unless this is root
+        assert!(process_alive("1"));
+        assert!(!process_alive(""));
+        assert!(!process_alive("1; true"));
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use bollard::container::{
+    Config, CreateContainerOptions, ListContainersOptions, RemoveContainerOptions,
+    StartContainerOptions,
+};
+use bollard::models::{ContainerSummary, HostConfig, RestartPolicy, RestartPolicyNameEnum};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    copy_in_tar, copy_out_tar, default_error, run_reporting, Container, Error, ExecStream, Image,
+    Progress,
+};
+
+/// AsyncDockerClient is a docker client whose calls can run concurrently,
+/// it is cheap to clone. Calls need a tokio runtime, see `crate::runtime`
+#[derive(Clone)]
+pub struct AsyncDockerClient {
+    inner_cli: bollard::Docker,
+    server: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl Default for AsyncDockerClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsyncDockerClient {
+    pub fn new() -> Self {
+        Self::new_with_logininfo(None, None, None)
+    }
+
+    pub fn new_with_logininfo(
+        server_: Option<&str>,
+        username_: Option<&str>,
+        password_: Option<&str>,
+    ) -> Self {
+        // connecting is lazy, it only fails on a malformed DOCKER_HOST or a missing socket
+        let inner_cli =
+            bollard::Docker::connect_with_local_defaults().expect("connect docker daemon");
+        AsyncDockerClient {
+            inner_cli,
+            server: server_.map(String::from),
+            username: username_.map(String::from),
+            password: password_.map(String::from),
+            progress: None,
+        }
+    }
+
+    /// with_progress report copies, pushes and pulls to `progress`
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub async fn ps(&self, all: bool) -> Result<Vec<Container>, Error> {
+        self.list(all, HashMap::new()).await
+    }
+
+    /// list containers with `label`, which is `key` or `key=value`
+    pub async fn ps_with_label(&self, all: bool, label: &str) -> Result<Vec<Container>, Error> {
+        let mut filters = HashMap::new();
+        filters.insert("label".to_owned(), vec![label.to_owned()]);
+        self.list(all, filters).await
+    }
+
+    async fn list(
+        &self,
+        all: bool,
+        filters: HashMap<String, Vec<String>>,
+    ) -> Result<Vec<Container>, Error> {
+        let opt = ListContainersOptions {
+            all,
+            filters,
+            ..Default::default()
+        };
+        self.inner_cli
+            .list_containers(Some(opt))
+            .await
+            .map(|cs| cs.into_iter().map(container).collect())
+            .map_err(default_error)
+    }
+
+    pub async fn image(&self, name: &str) -> Result<Image, Error> {
+        let image = self
+            .inner_cli
+            .inspect_image(name)
+            .await
+            .map_err(default_error)?;
+        Ok(Image {
+            id: image.id.unwrap_or_default(),
+        })
+    }
+
+    /// repo digests of local image, empty if image never pushed or pulled
+    pub async fn image_digests(&self, image: &str) -> Result<Vec<String>, Error> {
+        let format = "{{join .RepoDigests \" \"}}";
+        let out = docker(
+            &["image", "inspect", "--format", format, image],
+            Error::InspectError,
+        )
+        .await?;
+        Ok(String::from_utf8_lossy(&out)
+            .split_whitespace()
+            .map(|d| d.to_owned())
+            .collect())
+    }
+
+    /// tag `image` as `target`
+    pub async fn tag(&self, image: &str, target: &str) -> Result<(), Error> {
+        docker(&["tag", image, target], Error::TagError)
+            .await
+            .map(|_| ())
+    }
+
+    /// local tags of `repository` as (tag, creation time)
+    pub async fn tags(&self, repository: &str) -> Result<Vec<(String, String)>, Error> {
+        let format = "{{.Tag}} {{.CreatedAt}}";
+        let out = docker(
+            &["images", "--format", format, repository],
+            Error::InspectError,
+        )
+        .await?;
+        Ok(String::from_utf8_lossy(&out)
+            .lines()
+            .filter_map(|l| {
+                let mut fields = l.splitn(2, ' ');
+                let tag = fields.next()?;
+                if tag == "<none>" {
+                    return None;
+                }
+                Some((tag.to_owned(), fields.next().unwrap_or("").to_owned()))
+            })
+            .collect())
+    }
+
+    /// history of `image`, newest layer first, as (layer id, commit message).
+    /// Layer id is `<missing>` for layers not built on this host
+    pub async fn history(&self, image: &str) -> Result<Vec<(String, String)>, Error> {
+        let format = "{{.ID}} {{.Comment}}";
+        let out = docker(
+            &["history", "--no-trunc", "--format", format, image],
+            Error::InspectError,
+        )
+        .await?;
+        Ok(String::from_utf8_lossy(&out)
+            .lines()
+            .map(|l| {
+                let mut fields = l.splitn(2, ' ');
+                let id = fields.next().unwrap_or("").to_owned();
+                let comment = fields.next().unwrap_or("").to_owned();
+                (id, comment)
+            })
+            .collect())
+    }
+
+    /// digests of `image` in registry, there are several if it is a manifest list
+    pub async fn remote_digests(&self, image: &str) -> Result<Vec<String>, Error> {
+        let out = docker(&["manifest", "inspect", "-v", image], Error::InspectError).await?;
+        let v: serde_json::Value =
+            serde_json::from_slice(&out).map_err(|e| Error::InspectError(e.to_string()))?;
+        let manifests = match v {
+            serde_json::Value::Array(vs) => vs,
+            v => vec![v],
+        };
+        Ok(manifests
+            .iter()
+            .filter_map(|m| m["Descriptor"]["digest"].as_str())
+            .map(|d| d.to_owned())
+            .collect())
+    }
+
+    /// Copy host file into container
+    pub async fn copy_in(&self, container: &str, src: &Path, dst: &Path) -> Result<(), Error> {
+        match self.progress {
+            Some(ref progress) => {
+                let (container, src, dst) = (container.to_owned(), src.to_owned(), dst.to_owned());
+                let progress = progress.clone();
+                tokio::task::spawn_blocking(move || {
+                    copy_in_tar(&container, &src, &dst, progress.as_ref())
+                })
+                .await
+                .map_err(default_error)?
+            }
+            None => {
+                let dst = in_container(container, dst);
+                docker(&[OsStr::new("cp"), src.as_os_str(), &dst], Error::CopyError)
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+
+    pub async fn copy_out(&self, container: &str, src: &Path, dst: &Path) -> Result<(), Error> {
+        match self.progress {
+            Some(ref progress) => {
+                let (container, src, dst) = (container.to_owned(), src.to_owned(), dst.to_owned());
+                let progress = progress.clone();
+                tokio::task::spawn_blocking(move || {
+                    copy_out_tar(&container, &src, &dst, progress.as_ref())
+                })
+                .await
+                .map_err(default_error)?
+            }
+            None => {
+                let src = in_container(container, src);
+                docker(&[OsStr::new("cp"), &src, dst.as_os_str()], Error::CopyError)
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+
+    pub async fn remove_file(&self, container: &str, path: &Path) -> Result<(), Error> {
+        let args = [
+            OsStr::new("exec"),
+            OsStr::new(container),
+            OsStr::new("rm"),
+            OsStr::new("-rf"),
+            path.as_os_str(),
+        ];
+        docker(&args, default_error).await.map(|_| ())
+    }
+
+    pub async fn create(&self, image: &str) -> Result<Container, Error> {
+        self.create_with_labels(image, None, &HashMap::new(), None)
+            .await
+    }
+
+    /// create container named `name`, docker generates a name if it is None.
+    /// `restart_policy` is one of docker's restart policies, e.g. `unless-stopped`
+    pub async fn create_with_labels(
+        &self,
+        image: &str,
+        name: Option<&str>,
+        labels: &HashMap<&str, &str>,
+        restart_policy: Option<&str>,
+    ) -> Result<Container, Error> {
+        let restart_policy = match restart_policy {
+            None => None,
+            Some(policy) => Some(RestartPolicy {
+                name: Some(
+                    policy
+                        .parse::<RestartPolicyNameEnum>()
+                        .map_err(|e| Error::CreateError(e.to_string()))?,
+                ),
+                maximum_retry_count: None,
+            }),
+        };
+        let config = Config {
+            image: Some(image.to_owned()),
+            labels: Some(
+                labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            tty: Some(true),
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            host_config: Some(HostConfig {
+                restart_policy,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let opt = name.map(|name| CreateContainerOptions {
+            name: name.to_owned(),
+            platform: None,
+        });
+        let info = self
+            .inner_cli
+            .create_container(opt, config)
+            .await
+            .map_err(default_error)?;
+        self.ps(true)
+            .await?
+            .into_iter()
+            .find(|c| c.id == info.id)
+            .ok_or_else(|| Error::CreateError(format!("not found created container: {}", info.id)))
+    }
+
+    pub async fn start(&self, container: &str) -> Result<(), Error> {
+        self.inner_cli
+            .start_container(container, None::<StartContainerOptions<String>>)
+            .await
+            .map_err(default_error)
+    }
+
+    pub async fn commit(
+        &self,
+        container: &str,
+        message: &str,
+        new_image: &str,
+    ) -> Result<(), Error> {
+        // the message is an argument of its own, so it is never parsed by a shell
+        docker(
+            &["commit", "-m", message, container, new_image],
+            Error::CommitError,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// exec run shell command `cmd` in `container`, and return its stdout and stderr
+    pub async fn exec(&self, container: &str, cmd: &str) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        tokio::process::Command::new("docker")
+            .args(["exec", "-i", container, "sh", "-c", cmd])
+            .output()
+            .await
+            .map(|r| (r.stdout, r.stderr))
+            .map_err(default_error)
+    }
+
+    /// exec_stream run `cmd` in `container` with `input` on its stdin,
+    /// and return a reader of its stdout. The reader fails at the end if `cmd` fails.
+    /// It is not async, the reader blocks
+    pub fn exec_stream(
+        &self,
+        container: &str,
+        cmd: &str,
+        input: Vec<u8>,
+    ) -> Result<ExecStream, Error> {
+        use std::io::Write;
+        let mut child = std::process::Command::new("docker")
+            .args(["exec", "-i", container, "sh", "-c", cmd])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(default_error)?;
+        let mut stdin = child.stdin.take().unwrap();
+        // written from another thread, else a big input and output would block each other
+        std::thread::spawn(move || stdin.write_all(&input));
+        // stderr is drained as it comes for the same reason, a full pipe would stall `cmd`
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr = std::thread::spawn(move || {
+            let mut err = String::new();
+            let _ = std::io::Read::read_to_string(&mut stderr, &mut err);
+            err
+        });
+        let stdout = child.stdout.take().unwrap();
+        Ok(ExecStream {
+            child,
+            stdout,
+            stderr: Some(stderr),
+        })
+    }
+
+    pub async fn remove(&self, container: &str) -> Result<(), Error> {
+        let opt = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        self.inner_cli
+            .remove_container(container, Some(opt))
+            .await
+            .map_err(default_error)
+    }
+
+    pub async fn remove_image(&self, image: &str) -> Result<(), Error> {
+        docker(&["rmi", image], Error::RemoveImageError)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn squash(&self, image: &str, new_image: &str) -> Result<(), Error> {
+        let c = self.create(image).await?;
+        let r = export_import(&c.id, new_image).await;
+        let _ = self.remove(&c.id).await;
+        r
+    }
+
+    /// reporting run docker push or pull of `image` on a blocking thread
+    async fn reporting(
+        &self,
+        action: &'static str,
+        image: &str,
+        quiet_stderr: bool,
+    ) -> Result<std::process::ExitStatus, Error> {
+        let image = image.to_owned();
+        let progress = self.progress.clone();
+        tokio::task::spawn_blocking(move || {
+            run_reporting(action, &image, quiet_stderr, progress.as_deref())
+        })
+        .await
+        .map_err(default_error)?
+    }
+
+    /// login with the login info if there is all of it
+    async fn login(&self) -> Option<Result<(), Error>> {
+        let (server, username, password) = (
+            self.server.as_ref()?,
+            self.username.as_ref()?,
+            self.password.as_ref()?,
+        );
+        Some(login(server, username, password).await)
+    }
+
+    pub async fn pull(&self, image: &str) -> Result<(), Error> {
+        let r = self.reporting("pull", image, false).await?;
+        if r.success() {
+            return Ok(());
+        }
+        match self.login().await {
+            None => return Err(Error::PushError(format!("code: {:?}", r.code()))),
+            Some(login) => login?,
+        }
+        // login succ, retry pull
+        docker(&["pull", image], Error::PushError).await.map(|_| ())
+    }
+
+    // it maybe long duration, so print docker push's processing stdout
+    pub async fn push(&self, image: &str) -> Result<(), Error> {
+        let status = self.reporting("push", image, true).await?;
+        if status.success() {
+            return Ok(());
+        }
+        match self.login().await {
+            None => return Err(Error::PushError(format!("code: {:?}", status.code()))),
+            Some(login) => login?,
+        }
+
+        // login succ, retry push
+        let output = tokio::process::Command::new("docker")
+            .args(["push", image])
+            .stderr(Stdio::piped())
+            .stdout(Stdio::inherit())
+            .output()
+            .await
+            .map_err(|e| Error::PushError(e.to_string()))?;
+        if !output.status.success() {
+            let errstr = String::from_utf8_lossy(&output.stderr).to_string();
+            return Err(Error::PushError(errstr));
+        }
+        Ok(())
+    }
+}
+
+/// docker run docker with `args` and return its stdout,
+/// or its stderr as the error made by `fail`.
+/// There is no shell in between, so args are never parsed again
+async fn docker<S: AsRef<OsStr>>(args: &[S], fail: fn(String) -> Error) -> Result<Vec<u8>, Error> {
+    let r = tokio::process::Command::new("docker")
+        .args(args)
+        .output()
+        .await
+        .map_err(default_error)?;
+    if !r.status.success() {
+        let errstr = String::from_utf8_lossy(&r.stderr).to_string();
+        return Err(fail(errstr));
+    }
+    Ok(r.stdout)
+}
+
+/// in_container is the `docker cp` argument of `path` in `container`
+fn in_container(container: &str, path: &Path) -> OsString {
+    let mut arg = OsString::from(container);
+    arg.push(":");
+    arg.push(path);
+    arg
+}
+
+/// export_import pipe `docker export` of `container` to `docker import` as `image`
+async fn export_import(container: &str, image: &str) -> Result<(), Error> {
+    let mut export = tokio::process::Command::new("docker")
+        .args(["export", container])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(default_error)?;
+    let stdout: Stdio = export
+        .stdout
+        .take()
+        .unwrap()
+        .try_into()
+        .map_err(default_error)?;
+    let import = tokio::process::Command::new("docker")
+        .args(["import", "-", image])
+        .stdin(stdout)
+        .output()
+        .await
+        .map_err(default_error)?;
+    let export = export.wait_with_output().await.map_err(default_error)?;
+    for r in [&export, &import] {
+        if !r.status.success() {
+            let errstr = String::from_utf8_lossy(&r.stderr).to_string();
+            return Err(Error::SquashError(errstr));
+        }
+    }
+    Ok(())
+}
+
+/// login to `server`, the password goes on stdin so it is not in the process list
+async fn login(server: &str, username: &str, password: &str) -> Result<(), Error> {
+    let mut child = tokio::process::Command::new("docker")
+        .args(["login", server, "--username", username, "--password-stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(default_error)?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(password.as_bytes())
+        .await
+        .map_err(default_error)?;
+    drop(stdin);
+    let r = child.wait_with_output().await.map_err(default_error)?;
+    if !r.status.success() {
+        return Err(Error::LoginError(
+            String::from_utf8_lossy(&r.stderr).to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn container(c: ContainerSummary) -> Container {
+    Container {
+        id: c.id.unwrap_or_default(),
+        names: c.names.unwrap_or_default(),
+        image: c.image.unwrap_or_default(),
+        labels: c.labels.unwrap_or_default(),
+        status: c.status.unwrap_or_default(),
+        created: c.created.unwrap_or_default(),
+    }
+}
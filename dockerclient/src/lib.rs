@@ -1,8 +1,10 @@
-extern crate shiplift;
-extern crate tokio;
-extern crate tokio_core;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{BufRead, Read, Write};
+use std::sync::{Arc, OnceLock};
 
-use tokio::prelude::Future;
+mod asynchronous;
+pub use crate::asynchronous::AsyncDockerClient;
 
 #[derive(Debug)]
 pub enum Error {
@@ -13,26 +15,91 @@ pub enum Error {
     PushError(String),
     LoginError(String),
     RemoveImageError(String),
+    InspectError(String),
+    TagError(String),
     DefaultError(String),
 }
 
-pub type Container = shiplift::rep::Container;
-pub type Image = shiplift::rep::ImageDetails;
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (what, e) = match self {
+            Error::SquashError(e) => ("squash", e),
+            Error::CommitError(e) => ("commit", e),
+            Error::CreateError(e) => ("create", e),
+            Error::CopyError(e) => ("copy", e),
+            Error::PushError(e) => ("push", e),
+            Error::LoginError(e) => ("login", e),
+            Error::RemoveImageError(e) => ("remove image", e),
+            Error::InspectError(e) => ("inspect", e),
+            Error::TagError(e) => ("tag", e),
+            Error::DefaultError(e) => return write!(f, "{}", e.trim()),
+        };
+        write!(f, "{} failed: {}", what, e.trim())
+    }
+}
+
+/// Progress observe transfers of `DockerClient`, e.g. to draw progress bars.
+/// A task is a copy, or a layer of a push or pull. Only copies have sizes and updates,
+/// layers of pushes and pulls have statuses alone, so they have no rate or ETA
+pub trait Progress: Send + Sync {
+    /// `task` starts, `total` is its size in bytes if known
+    fn start(&self, task: &str, total: Option<u64>);
+    /// `done` bytes of `task` are transferred so far
+    fn update(&self, task: &str, done: u64);
+    /// `status` of a layer changes, like `Pushing` or `Pull complete`.
+    /// docker prints no byte counts of layers when its output is not a terminal
+    fn status(&self, task: &str, status: &str);
+    fn finish(&self, task: &str);
+}
+
+#[derive(Debug, Clone)]
+pub struct Container {
+    pub id: String,
+    pub names: Vec<String>,
+    pub image: String,
+    pub labels: HashMap<String, String>,
+    /// like `Up 2 hours`, `Exited (0) 3 days ago` or `Created`
+    pub status: String,
+    /// unix timestamp
+    pub created: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub id: String,
+}
+
+/// runtime is shared by every blocking call of every `DockerClient`
+pub fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("build tokio runtime")
+    })
+}
+
+/// block_on run `future` on the shared runtime. Don't call it from async code
+fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().block_on(future)
+}
+
+/// DockerClient is the blocking facade of `AsyncDockerClient`
 pub struct DockerClient {
-    inner_cli: shiplift::Docker,
-    server: Option<String>,
-    username: Option<String>,
-    password: Option<String>,
+    inner: AsyncDockerClient,
+}
+
+impl Default for DockerClient {
+    fn default() -> Self {
+        DockerClient::new()
+    }
 }
 
 impl DockerClient {
     pub fn new() -> Self {
-        let inner_cli = shiplift::Docker::new();
         DockerClient {
-            inner_cli: inner_cli,
-            server: None,
-            username: None,
-            password: None,
+            inner: AsyncDockerClient::new(),
         }
     }
 
@@ -41,48 +108,57 @@ impl DockerClient {
         username_: Option<&str>,
         password_: Option<&str>,
     ) -> Self {
-        let inner_cli = shiplift::Docker::new();
-        let server = server_.map(String::from);
-        let username = username_.map(String::from);
-        let password = password_.map(String::from);
         DockerClient {
-            inner_cli,
-            server,
-            username,
-            password,
+            inner: AsyncDockerClient::new_with_logininfo(server_, username_, password_),
+        }
+    }
+
+    /// with_progress report copies, pushes and pulls to `progress`
+    pub fn with_progress(self, progress: Arc<dyn Progress>) -> Self {
+        DockerClient {
+            inner: self.inner.with_progress(progress),
         }
     }
 
     pub fn ps(&self, all: bool) -> Result<Vec<Container>, Error> {
-        let opt = if all {
-            shiplift::ContainerListOptions::builder().all().build()
-        } else {
-            shiplift::ContainerListOptions::builder().build()
-        };
-        let fut = self
-            .inner_cli
-            .containers()
-            .list(&opt)
-            .map(move |containers| {
-                let mut cs = Vec::new();
-                for c in containers {
-                    cs.push(c);
-                }
-                cs
-            })
-            .map_err(|e| Error::DefaultError(e.to_string()));
-        tokio_core::reactor::Core::new().unwrap().run(fut)
+        block_on(self.inner.ps(all))
+    }
+
+    /// list containers with `label`, which is `key` or `key=value`
+    pub fn ps_with_label(&self, all: bool, label: &str) -> Result<Vec<Container>, Error> {
+        block_on(self.inner.ps_with_label(all, label))
     }
 
     pub fn image(&self, name: &str) -> Result<Image, Error> {
-        let fut = self
-            .inner_cli
-            .images()
-            .get(name)
-            .inspect()
-            .map_err(|e| Error::DefaultError(e.to_string()));
-        tokio_core::reactor::Core::new().unwrap().run(fut)
+        block_on(self.inner.image(name))
+    }
+
+    /// repo digests of local image, empty if image never pushed or pulled
+    pub fn image_digests(&self, image: &str) -> Result<Vec<String>, Error> {
+        block_on(self.inner.image_digests(image))
+    }
+
+    /// tag `image` as `target`
+    pub fn tag(&self, image: &str, target: &str) -> Result<(), Error> {
+        block_on(self.inner.tag(image, target))
+    }
+
+    /// local tags of `repository` as (tag, creation time)
+    pub fn tags(&self, repository: &str) -> Result<Vec<(String, String)>, Error> {
+        block_on(self.inner.tags(repository))
+    }
+
+    /// history of `image`, newest layer first, as (layer id, commit message).
+    /// Layer id is `<missing>` for layers not built on this host
+    pub fn history(&self, image: &str) -> Result<Vec<(String, String)>, Error> {
+        block_on(self.inner.history(image))
+    }
+
+    /// digests of `image` in registry, there are several if it is a manifest list
+    pub fn remote_digests(&self, image: &str) -> Result<Vec<String>, Error> {
+        block_on(self.inner.remote_digests(image))
     }
+
     /// Copy host file into container
     pub fn copy_in(
         &self,
@@ -90,7 +166,7 @@ impl DockerClient {
         src: &std::path::Path,
         dst: &std::path::Path,
     ) -> Result<(), Error> {
-        copy(container, true, src, dst)
+        block_on(self.inner.copy_in(container, src, dst))
     }
 
     pub fn copy_out(
@@ -99,276 +175,377 @@ impl DockerClient {
         src: &std::path::Path,
         dst: &std::path::Path,
     ) -> Result<(), Error> {
-        copy(container, false, src, dst)
+        block_on(self.inner.copy_out(container, src, dst))
     }
 
     pub fn remove_file(&self, container: &str, path: &std::path::Path) -> Result<(), Error> {
-        let cmd = format!("rm -rf {}", path.display());
-        self.exec(container, &cmd).map(|_| ())
+        block_on(self.inner.remove_file(container, path))
     }
 
     pub fn create(&self, image: &str) -> Result<Container, Error> {
-        let fut = self
-            .inner_cli
-            .containers()
-            .create(
-                &shiplift::ContainerOptions::builder(image)
-                    .tty(true)
-                    .attach_stdin(true)
-                    .attach_stdout(true)
-                    .attach_stderr(true)
-                    .build(),
-            )
-            .map_err(|e| Error::DefaultError(e.to_string()))
-            .and_then(|info| {
-                self.inner_cli
-                    .containers()
-                    .list(&shiplift::ContainerListOptions::builder().all().build())
-                    .map_err(|e| Error::DefaultError(e.to_string()))
-                    .and_then(move |containers| {
-                        for c in containers {
-                            if c.id == info.id {
-                                return Ok(c);
-                            }
-                        }
-                        let e = format!("not found created container: {}", info.id);
-                        Err(Error::CreateError(e))
-                    })
-            });
-        tokio_core::reactor::Core::new().unwrap().run(fut)
+        block_on(self.inner.create(image))
+    }
+
+    /// create container named `name`, docker generates a name if it is None.
+    /// `restart_policy` is one of docker's restart policies, e.g. `unless-stopped`
+    pub fn create_with_labels(
+        &self,
+        image: &str,
+        name: Option<&str>,
+        labels: &HashMap<&str, &str>,
+        restart_policy: Option<&str>,
+    ) -> Result<Container, Error> {
+        block_on(
+            self.inner
+                .create_with_labels(image, name, labels, restart_policy),
+        )
     }
 
     pub fn start(&self, container: &str) -> Result<(), Error> {
-        let fut = self
-            .inner_cli
-            .containers()
-            .get(container)
-            .start()
-            .map_err(|e| Error::DefaultError(e.to_string()));
-        tokio_core::reactor::Core::new().unwrap().run(fut)
+        block_on(self.inner.start(container))
     }
 
     pub fn commit(&self, container: &str, message: &str, new_image: &str) -> Result<(), Error> {
-        let cmd = format!(
-            "docker commit -m \"{}\" {} {}",
-            message, container, new_image
-        );
-        let r = std::process::Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .output()
-            .map_err(|e| Error::DefaultError(e.to_string()))?;
-        if !r.status.success() {
-            let errstr = String::from_utf8_lossy(&r.stderr).to_string();
-            return Err(Error::CommitError(errstr));
-        }
-        Ok(())
+        block_on(self.inner.commit(container, message, new_image))
     }
 
     pub fn exec(&self, container: &str, cmd: &str) -> Result<(Vec<u8>, Vec<u8>), Error> {
-        let cmd = format!("docker exec -i {} sh -c \"{}\"", container, cmd);
-        std::process::Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .output()
-            .map(|r| (r.stdout, r.stderr))
-            .map_err(|e| Error::DefaultError(e.to_string()))
+        block_on(self.inner.exec(container, cmd))
+    }
+
+    /// exec_stream run `cmd` in `container` with `input` on its stdin,
+    /// and return a reader of its stdout. The reader fails at the end if `cmd` fails
+    pub fn exec_stream(
+        &self,
+        container: &str,
+        cmd: &str,
+        input: Vec<u8>,
+    ) -> Result<ExecStream, Error> {
+        self.inner.exec_stream(container, cmd, input)
     }
 
     pub fn remove(&self, container: &str) -> Result<(), Error> {
-        let fut = self
-            .inner_cli
-            .containers()
-            .get(container)
-            .remove(shiplift::RmContainerOptions::builder().force(true).build())
-            .map_err(|e| Error::DefaultError(e.to_string()));
-        tokio_core::reactor::Core::new().unwrap().run(fut)
+        block_on(self.inner.remove(container))
     }
 
     pub fn remove_image(&self, image: &str) -> Result<(), Error> {
-        let cmd = format!("docker rmi {}", image);
-        let r = std::process::Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .output()
-            .map_err(|e| Error::DefaultError(e.to_string()))?;
-        if !r.status.success() {
-            let errstr = String::from_utf8_lossy(&r.stderr).to_string();
-            return Err(Error::RemoveImageError(errstr));
-        }
-        Ok(())
+        block_on(self.inner.remove_image(image))
     }
 
     pub fn squash(&self, image: &str, new_image: &str) -> Result<(), Error> {
-        let c = self.create(image)?;
-        let cmd = format!("docker export {} | docker import - {}", &c.id, new_image);
-        let r = std::process::Command::new("sh")
-            .arg("-c")
-            .arg(cmd)
-            .output()
-            .map_err(|e| Error::DefaultError(e.to_string()))?;
-        if !r.status.success() {
-            let _ = self.remove(&c.id);
-            let errstr = String::from_utf8_lossy(&r.stderr).to_string();
-            return Err(Error::SquashError(errstr));
-        }
-        let _ = self.remove(&c.id);
-        Ok(())
+        block_on(self.inner.squash(image, new_image))
     }
 
     pub fn pull(&self, image: &str) -> Result<(), Error> {
-        let cmd = format!("docker pull {}", image);
-        let r = std::process::Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .status()
-            .map_err(|e| Error::DefaultError(e.to_string()))?;
-
-        if r.success() {
-            return Ok(());
-        }
-        if !r.success()
-            && self.server.is_some()
-            && self.username.is_some()
-            && self.password.is_some()
-        {
-            let login_cmd = format!(
-                "docker login {} --username={}, --password={}",
-                self.server.as_ref().unwrap(),
-                self.username.as_ref().unwrap(),
-                self.password.as_ref().unwrap()
-            );
-            let output = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(login_cmd)
-                .output()
-                .map_err(|e| Error::DefaultError(e.to_string()))?;
-            if !output.status.success() {
-                let errstr = String::from_utf8_lossy(&output.stderr).to_string();
-                return Err(Error::LoginError(errstr));
-            }
-        } else if !r.success() {
-            return Err(Error::PushError(format!("code: {:?}", r.code())));
-        }
-        // login succ, retry pull
-        std::process::Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .output()
-            .map_err(|e| Error::DefaultError(e.to_string()))
-            .and_then(|r| {
-                if !r.status.success() {
-                    let errstr = String::from_utf8_lossy(&r.stderr).to_string();
-                    return Err(Error::PushError(errstr));
-                }
-                Ok(())
-            })
+        block_on(self.inner.pull(image))
     }
 
     // it maybe long duration, so print docker push's processing stdout
     pub fn push(&self, image: &str) -> Result<(), Error> {
-        let cmd = format!("docker push {}", image);
-        let mut r = std::process::Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .stderr(std::process::Stdio::null())
-            .stdout(std::process::Stdio::inherit())
-            .spawn()
-            .map_err(|e| Error::DefaultError(e.to_string()))?;
-        let status = r.wait().map_err(|e| Error::DefaultError(e.to_string()))?;
-        if status.success() {
-            return Ok(());
-        }
-        if (!status.success())
-            && self.server.is_some()
-            && self.username.is_some()
-            && self.password.is_some()
-        {
-            let login_cmd = format!(
-                "docker login {} --username={} --password={}",
-                self.server.as_ref().unwrap(),
-                self.username.as_ref().unwrap(),
-                self.password.as_ref().unwrap()
-            );
-            let output = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(login_cmd)
-                .output()
-                .map_err(|e| Error::DefaultError(e.to_string()))?;
-            if !output.status.success() {
-                let errstr = String::from_utf8_lossy(&output.stderr).to_string();
-                return Err(Error::LoginError(errstr));
-            }
-        } else if !status.success() {
-            return Err(Error::PushError(format!("code: {:?}", status.code())));
-        }
+        block_on(self.inner.push(image))
+    }
+}
+
+/// parse_layer_status split a line of docker push or pull output like `<layer>: <status>`
+fn parse_layer_status(line: &str) -> Option<(&str, &str)> {
+    let pos = line.find(": ")?;
+    let (layer, status) = (&line[..pos], &line[pos + 2..]);
+    if layer.len() == 12 && layer.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some((layer, status.trim()))
+    } else {
+        None
+    }
+}
+
+fn layer_done(status: &str) -> bool {
+    status == "Pushed"
+        || status == "Pull complete"
+        || status == "Layer already exists"
+        || status == "Already exists"
+        || status.starts_with("Mounted from")
+}
+
+/// Counter count bytes read or written through it, and report them to `progress`
+struct Counter<'a, T> {
+    inner: T,
+    task: String,
+    progress: &'a dyn Progress,
+    done: u64,
+}
+
+impl<'a, T: Write> Write for Counter<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.done += n as u64;
+        self.progress.update(&self.task, self.done);
+        Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-        // login succ, retry push
-
-        std::process::Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .stderr(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::inherit())
-            .spawn()
-            .map_err(|e| Error::PushError(e.to_string()))
-            .and_then(|r| {
-                let output = r
-                    .wait_with_output()
-                    .map_err(|e| Error::PushError(e.to_string()))?;
-                if !output.status.success() {
-                    let errstr = String::from_utf8_lossy(&output.stderr).to_string();
-                    return Err(Error::PushError(errstr));
-                }
-                Ok(())
+impl<'a, T: Read> Read for Counter<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        self.progress.update(&self.task, self.done);
+        Ok(n)
+    }
+}
+
+fn tree_size(path: &std::path::Path) -> u64 {
+    if path.is_dir() {
+        std::fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| tree_size(&e.path()))
+                    .sum()
             })
+            .unwrap_or(0)
+    } else {
+        path.metadata().map(|m| m.len()).unwrap_or(0)
     }
 }
 
-fn copy(
+fn copy_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::CopyError(e.to_string())
+}
+
+/// copy_in_tar is `docker cp` from host, with the tar stream made here so its bytes are counted.
+/// Same as `docker cp`, `src/.` copies the content of `src` into `dst`
+fn copy_in_tar(
     container: &str,
-    to_container: bool,
     src: &std::path::Path,
     dst: &std::path::Path,
+    progress: &dyn Progress,
 ) -> Result<(), Error> {
-    let cmd = if to_container {
-        format!(
-            "docker cp {} {}:{}",
-            src.display(),
-            container,
-            dst.display()
+    let contents = src.to_string_lossy().ends_with("/.");
+    let extract_dir = if contents {
+        dst
+    } else {
+        dst.parent().unwrap_or_else(|| std::path::Path::new("/"))
+    };
+    let mut dst_arg = std::ffi::OsString::from(container);
+    dst_arg.push(":");
+    dst_arg.push(extract_dir);
+    let mut child = std::process::Command::new("docker")
+        .arg("cp")
+        .arg("-")
+        .arg(dst_arg)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(copy_error)?;
+    let task = format!("copy {}", dst.display());
+    progress.start(&task, Some(tree_size(src)));
+    {
+        let counter = Counter {
+            inner: child.stdin.take().unwrap(),
+            task: task.clone(),
+            progress,
+            done: 0,
+        };
+        let mut builder = tar::Builder::new(counter);
+        let mut append = |name: &std::path::Path, path: &std::path::Path| {
+            if path.is_dir() {
+                builder.append_dir_all(name, path)
+            } else {
+                builder.append_path_with_name(path, name)
+            }
+        };
+        if contents {
+            for entry in std::fs::read_dir(src).map_err(copy_error)? {
+                let entry = entry.map_err(copy_error)?;
+                append(std::path::Path::new(&entry.file_name()), &entry.path())
+                    .map_err(copy_error)?;
+            }
+        } else {
+            let name = dst.file_name().unwrap_or(src.as_os_str());
+            append(std::path::Path::new(name), src).map_err(copy_error)?;
+        }
+        builder.finish().map_err(copy_error)?;
+    }
+    let output = child.wait_with_output().map_err(copy_error)?;
+    progress.finish(&task);
+    if !output.status.success() {
+        return Err(Error::CopyError(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// copy_out_tar is `docker cp` to host, with the tar stream unpacked here so its bytes are counted
+fn copy_out_tar(
+    container: &str,
+    src: &std::path::Path,
+    dst: &std::path::Path,
+    progress: &dyn Progress,
+) -> Result<(), Error> {
+    // same as `docker cp`: into `dst` if it is a dir, else as `dst`
+    let (parent, root) = if dst.is_dir() {
+        (
+            dst.to_path_buf(),
+            src.file_name().unwrap_or_default().to_owned(),
         )
     } else {
-        format!(
-            "docker cp {}:{} {}",
-            container,
-            src.display(),
-            dst.display()
+        (
+            dst.parent()
+                .unwrap_or_else(|| std::path::Path::new("."))
+                .to_path_buf(),
+            dst.file_name().unwrap_or_default().to_owned(),
         )
     };
-    let r = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .map_err(|e| Error::DefaultError(e.to_string()))?;
-    if !r.status.success() {
-        let errstr = String::from_utf8_lossy(&r.stderr).to_string();
-        return Err(Error::CopyError(errstr));
+    let mut src_arg = std::ffi::OsString::from(container);
+    src_arg.push(":");
+    src_arg.push(src);
+    let mut child = std::process::Command::new("docker")
+        .arg("cp")
+        .arg(src_arg)
+        .arg("-")
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(copy_error)?;
+    let task = format!("copy {}", src.display());
+    progress.start(&task, None);
+    let counter = Counter {
+        inner: child.stdout.take().unwrap(),
+        task: task.clone(),
+        progress,
+        done: 0,
     };
+    let mut archive = tar::Archive::new(counter);
+    for entry in archive.entries().map_err(copy_error)? {
+        let mut entry = entry.map_err(copy_error)?;
+        let path = entry.path().map_err(copy_error)?.to_path_buf();
+        // the root of the archive is `src`, it gets the name of `dst`
+        let mut components = path.components();
+        components.next();
+        let target = parent.join(&root).join(components.as_path());
+        if let Some(dir) = target.parent() {
+            std::fs::create_dir_all(dir).map_err(copy_error)?;
+        }
+        entry.unpack(&target).map_err(copy_error)?;
+    }
+    let output = child.wait_with_output().map_err(copy_error)?;
+    progress.finish(&task);
+    if !output.status.success() {
+        return Err(Error::CopyError(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
     Ok(())
 }
 
+/// ExecStream is the stdout of a command run by `DockerClient::exec_stream`
+pub struct ExecStream {
+    child: std::process::Child,
+    stdout: std::process::ChildStdout,
+    /// the thread reading stderr, it returns all of it
+    stderr: Option<std::thread::JoinHandle<String>>,
+}
+
+impl std::io::Read for ExecStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                let err = self
+                    .stderr
+                    .take()
+                    .and_then(|t| t.join().ok())
+                    .unwrap_or_default();
+                return Err(std::io::Error::other(err));
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// run_reporting run docker `action`, push or pull, of `image`. Its output goes to stdout,
+/// or to `progress` as layer statuses if there is one
+fn run_reporting(
+    action: &str,
+    image: &str,
+    quiet_stderr: bool,
+    progress: Option<&dyn Progress>,
+) -> Result<std::process::ExitStatus, Error> {
+    let stderr = if quiet_stderr {
+        std::process::Stdio::null()
+    } else {
+        std::process::Stdio::inherit()
+    };
+    let progress = match progress {
+        None => {
+            return std::process::Command::new("docker")
+                .args([action, image])
+                .stderr(stderr)
+                .stdout(std::process::Stdio::inherit())
+                .status()
+                .map_err(default_error)
+        }
+        Some(progress) => progress,
+    };
+    let mut child = std::process::Command::new("docker")
+        .args([action, image])
+        .stderr(stderr)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(default_error)?;
+    let stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+    let mut started = std::collections::HashSet::new();
+    for line in stdout.lines() {
+        let line = line.map_err(default_error)?;
+        if let Some((layer, status)) = parse_layer_status(&line) {
+            if started.insert(layer.to_owned()) {
+                progress.start(layer, None);
+            }
+            progress.status(layer, status);
+            if layer_done(status) {
+                progress.finish(layer);
+            }
+        }
+    }
+    child.wait().map_err(default_error)
+}
+
+fn default_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::DefaultError(e.to_string())
+}
+
 #[cfg(test)]
 mod tests {
-    use DockerClient;
+    use crate::DockerClient;
+
+    /// no_docker is true when there is no docker daemon to run these tests with
+    fn no_docker() -> bool {
+        let host = std::env::var("DOCKER_HOST").unwrap_or_default();
+        let socket = match host.trim_start_matches("unix://") {
+            "" => "/var/run/docker.sock",
+            socket => socket,
+        };
+        !std::path::Path::new(socket).exists()
+    }
+
     #[test]
     fn ps() {
+        if no_docker() {
+            return;
+        }
         let cli = DockerClient::new();
         let cs = cli.ps(true);
         assert!(cs.is_ok());
     }
     #[test]
     fn create() {
+        if no_docker() {
+            return;
+        }
         let cli = DockerClient::new();
         let r = cli.create("busybox:latest");
         println!("{:?}", r);
@@ -377,35 +554,73 @@ mod tests {
 
     #[test]
     fn copy_in() {
+        if no_docker() {
+            return;
+        }
         let cli = DockerClient::new();
         let c = cli.create("busybox:latest");
         assert!(c.is_ok());
         let r = cli.copy_in(
-            &*c.unwrap().id,
+            &c.unwrap().id,
             std::path::Path::new("Cargo.toml"),
             std::path::Path::new("/"),
         );
         println!("{:?}", r);
         assert!(r.is_ok());
     }
+    struct Quiet;
+    impl crate::Progress for Quiet {
+        fn start(&self, _: &str, _: Option<u64>) {}
+        fn update(&self, _: &str, _: u64) {}
+        fn status(&self, _: &str, _: &str) {}
+        fn finish(&self, _: &str) {}
+    }
+
+    #[test]
+    fn copy_in_with_progress() {
+        if no_docker() {
+            return;
+        }
+        // the tar stream is made here, its entry must be named as given
+        let cli = DockerClient::new().with_progress(std::sync::Arc::new(Quiet));
+        let c = cli.create("busybox:latest").unwrap();
+        cli.start(&c.id).unwrap();
+        let r = cli.copy_in(
+            &c.id,
+            std::path::Path::new("Cargo.toml"),
+            std::path::Path::new("/it's a \"toml\""),
+        );
+        assert!(r.is_ok());
+        let (out, _) = cli.exec(&c.id, "ls /").unwrap();
+        let _ = cli.remove(&c.id);
+        assert!(String::from_utf8_lossy(&out)
+            .lines()
+            .any(|l| l == "it's a \"toml\""));
+    }
     #[test]
     fn commit() {
+        if no_docker() {
+            return;
+        }
         let cli = DockerClient::new();
         let c_ = cli.create("busybox:latest");
         assert!(c_.is_ok());
         let c = c_.unwrap();
         let r = cli.copy_in(
-            &*c.id,
+            &c.id,
             std::path::Path::new("Cargo.toml"),
             std::path::Path::new("/"),
         );
         assert!(r.is_ok());
-        let r = cli.commit(&*c.id, "test commit", "test-commit:latest");
+        let r = cli.commit(&c.id, "test commit", "test-commit:latest");
         assert!(r.is_ok());
     }
 
     #[test]
     fn exec() {
+        if no_docker() {
+            return;
+        }
         let cli = DockerClient::new();
         let c_ = cli.create("busybox:latest");
         assert!(c_.is_ok());
@@ -415,21 +630,22 @@ mod tests {
         assert!(r_.is_ok());
         let r = r_.unwrap();
         let out: &[u8] = &r.0;
-        println!("{:?}", String::from_utf8_lossy(out).to_owned());
+        println!("{:?}", String::from_utf8_lossy(out));
     }
     #[test]
     fn squash() {
+        if no_docker() {
+            return;
+        }
         let cli = DockerClient::new();
         let c = cli.create("busybox").unwrap();
-        let _ = cli
-            .copy_in(
-                &c.id,
-                std::path::Path::new("Cargo.toml"),
-                std::path::Path::new("/"),
-            )
-            .unwrap();
-        let _ = cli.commit(&c.id, "add cargo toml", "test-squash").unwrap();
+        cli.copy_in(
+            &c.id,
+            std::path::Path::new("Cargo.toml"),
+            std::path::Path::new("/"),
+        )
+        .unwrap();
+        cli.commit(&c.id, "add cargo toml", "test-squash").unwrap();
         assert!(cli.squash("test-squash", "new-test-squash").is_ok());
     }
-
 }
@@ -2,14 +2,49 @@ extern crate db;
 #[macro_use]
 extern crate prettytable;
 
-use db::docker::ImageDrive;
-use db::DB;
+use db::docker::{Error, ImageDrive};
+use chrono::{Local, TimeZone};
+use db::{AddResult, Change, Conflict, RemoteState, Revision, DB};
 use prettytable::Table;
 use std::path::Path;
 
-pub fn list_entry(db: &ImageDrive) {
-    match db.entries() {
-        Err(e) => println!("list entries fail: {:?}", e),
+mod mount;
+mod progress;
+mod serve;
+mod ui;
+pub use crate::mount::mount;
+pub use crate::progress::progress;
+pub use crate::serve::serve;
+pub use crate::ui::ui;
+
+/// live_or_cached run `live` on the drive, or `cached` on its cached index if `offline`
+/// or docker is unreachable, telling which image and when the cache is of
+fn live_or_cached<T, L, C>(db: &ImageDrive, offline: bool, live: L, cached: C) -> Result<T, Error>
+where
+    L: FnOnce() -> Result<T, Error>,
+    C: FnOnce() -> Result<T, Error>,
+{
+    if !offline {
+        match live() {
+            Err(Error::DockerError(e)) => println!("docker unreachable: {:?}", e),
+            r => return r,
+        }
+    }
+    let stamp = db.cached_stamp()?;
+    println!(
+        "offline, cached index of image {} at {}",
+        stamp.digest.get(..19).unwrap_or(&stamp.digest),
+        format_time(stamp.refreshed)
+    );
+    cached()
+}
+
+pub fn list_entry(db: &ImageDrive, offline: bool) -> bool {
+    match live_or_cached(db, offline, || db.entries(), || db.cached_entries()) {
+        Err(e) => {
+            println!("list entries fail: {:?}", e);
+            false
+        }
         Ok(entries) => {
             let mut table = Table::new();
             table.add_row(row!["Entry", "Item Count"]);
@@ -18,28 +53,135 @@ pub fn list_entry(db: &ImageDrive) {
                 table.add_row(row![entry, "<unknown>"]);
             }
             table.printstd();
+            true
         }
     }
 }
 
-pub fn list_entry_item(db: &ImageDrive, entry: &str) {
-    match db.items(entry) {
-        Err(e) => println!("list items fail: {:?}", e),
+pub fn list_entry_item(db: &ImageDrive, entry: &str, offline: bool) -> bool {
+    let items = live_or_cached(
+        db,
+        offline,
+        || db.item_infos(entry),
+        || db.cached_item_infos(entry),
+    );
+    match items {
+        Err(e) => {
+            println!("list items fail: {:?}", e);
+            false
+        }
         Ok(items) => {
             let mut table = Table::new();
-            table.add_row(row!["Item"]);
+            table.add_row(row!["Item", "Size", "Stored"]);
             for item in items {
-                table.add_row(row![item]);
+                table.add_row(row![item.name, format_size(item.size), format_size(item.stored)]);
+            }
+            table.printstd();
+            true
+        }
+    }
+}
+
+pub fn find(db: &ImageDrive, pattern: &str, offline: bool) -> bool {
+    let found = live_or_cached(
+        db,
+        offline,
+        || db.find(pattern),
+        || db.cached_find(pattern),
+    );
+    match found {
+        Err(e) => {
+            println!("find fail: {:?}", e);
+            false
+        }
+        Ok(found) => {
+            let mut table = Table::new();
+            table.add_row(row!["Entry", "Item", "Size"]);
+            for (entry, item) in found {
+                table.add_row(row![entry, item.name, format_size(item.size)]);
             }
             table.printstd();
+            true
         }
     }
 }
 
-pub fn put(db: &ImageDrive, entry: &str, item: &str, rename: Option<&str>) {
-    match db.add(entry, Path::new(item), rename) {
-        Err(e) => println!("put item fail: {:?}", e),
-        Ok(r) => println!("{:?}", r),
+fn format_size(size: Option<u64>) -> String {
+    let size = match size {
+        None => return "<unknown>".to_owned(),
+        Some(size) => size as f64,
+    };
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut i = 0;
+    let mut s = size;
+    while s >= 1024.0 && i < units.len() - 1 {
+        s /= 1024.0;
+        i += 1;
+    }
+    if i == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", s, units[i])
+    }
+}
+
+pub fn put(
+    db: &ImageDrive,
+    entry: &str,
+    item: &str,
+    rename: Option<&str>,
+    conflict: Conflict,
+) -> bool {
+    match db.add(entry, Path::new(item), rename, conflict) {
+        Err(e) => {
+            println!("put item fail: {:?}", e);
+            false
+        }
+        Ok(AddResult::Replaced { item, old, new }) => {
+            println!("replaced {}/{}: {} -> {}", entry, item, old, new);
+            true
+        }
+        Ok(AddResult::Renamed(name)) => {
+            println!("{} existed, put as {}/{}", item, entry, name);
+            true
+        }
+        Ok(r) => {
+            println!("{:?}", r);
+            true
+        }
+    }
+}
+
+pub fn put_many(db: &ImageDrive, entry: &str, items: &[String], conflict: Conflict) -> bool {
+    let paths: Vec<&Path> = items.iter().map(Path::new).collect();
+    match db.add_many(entry, &paths, conflict) {
+        Err(e) => {
+            println!("put items fail: {:?}", e);
+            false
+        }
+        Ok(results) => {
+            let mut table = Table::new();
+            table.add_row(row!["File", "Result"]);
+            let mut failed = 0;
+            for (path, result) in results {
+                let result = match result {
+                    Ok(AddResult::Succ) => "added".to_owned(),
+                    Ok(AddResult::ExistedItem(name)) => format!("existed as {}", name),
+                    Ok(AddResult::Replaced { old, new, .. }) => {
+                        format!("replaced {} -> {}", old, new)
+                    }
+                    Ok(AddResult::Renamed(name)) => format!("added as {}", name),
+                    Err(e) => {
+                        failed += 1;
+                        format!("fail: {:?}", e)
+                    }
+                };
+                table.add_row(row![path.display(), result]);
+            }
+            table.printstd();
+            println!("{} files, {} failed", items.len(), failed);
+            true
+        }
     }
 }
 
@@ -50,21 +192,322 @@ pub fn export(db: &ImageDrive, entry: &str, dstdir: &str) {
     }
 }
 
-pub fn sync(db: &ImageDrive, from_remote: bool) {
+pub fn mirror(db: &ImageDrive, dir: &str, entry: &str, pull: bool, delete: bool) -> bool {
+    let result = if pull {
+        db.mirror_pull(entry, Path::new(dir), delete)
+    } else {
+        db.mirror_push(Path::new(dir), entry, delete)
+    };
+    match result {
+        Err(e) => {
+            println!("mirror fail: {:?}", e);
+            false
+        }
+        Ok(report) => {
+            for name in &report.transferred {
+                println!("\tcopied:  {}", name);
+            }
+            for name in &report.deleted {
+                println!("\tdeleted: {}", name);
+            }
+            println!(
+                "{} copied, {} deleted, {} unchanged",
+                report.transferred.len(),
+                report.deleted.len(),
+                report.unchanged
+            );
+            true
+        }
+    }
+}
+
+pub fn cat(db: &ImageDrive, entry: &str, item: &str) {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if let Err(e) = db.cat(entry, item, &mut out) {
+        println!("cat item fail: {:?}", e);
+    }
+}
+
+pub fn sync(db: &ImageDrive, from_remote: bool, message: Option<&str>) -> bool {
     match if from_remote {
         db.sync_from_remote()
     } else {
-        db.sync()
+        db.sync(message)
     } {
-        Err(e) => println!("sync fail: {:?}", e),
-        Ok(_) => println!("sync localDB to remoteDB successfully"),
+        Err(e) => {
+            println!("sync fail: {:?}", e);
+            false
+        }
+        Ok(_) => {
+            println!("sync localDB to remoteDB successfully");
+            true
+        }
     }
 }
 
-pub fn rm(db: &ImageDrive, entry: &str, file: Option<&str>) {
+pub fn rm(db: &ImageDrive, entry: &str, file: Option<&str>, recursive: bool) -> bool {
+    if file.is_none() && !recursive {
+        println!("{} is an entry, use `rm --recursive` to remove it with all its files", entry);
+        return false;
+    }
     match db.delete(entry, file) {
-        Err(e) => println!("rm entry (or file) fail: {:?}", e),
-        Ok(_) => println!("rm [{:?}]", entry.to_owned() + "/" + file.unwrap_or("")),
+        Err(e) => {
+            println!("rm entry (or file) fail: {:?}", e);
+            false
+        }
+        Ok(_) => {
+            println!("rm [{:?}]", entry.to_owned() + "/" + file.unwrap_or(""));
+            true
+        }
+    }
+}
+
+pub fn status(db: &ImageDrive, offline: bool) -> bool {
+    match live_or_cached(db, offline, || db.status(), || db.cached_status()) {
+        Err(e) => {
+            println!("status fail: {:?}", e);
+            false
+        }
+        Ok(status) => {
+            match status.remote {
+                RemoteState::UpToDate => println!("localDB image is up to date with remoteDB"),
+                RemoteState::Differ => println!("localDB image differs from remoteDB"),
+                RemoteState::Unknown => println!("localDB image state with remoteDB unknown"),
+            }
+            if status.changes.is_empty() {
+                println!("nothing to sync, localDB clean");
+                return true;
+            }
+            println!("changes not synced:");
+            print_changes(&status.changes);
+            true
+        }
+    }
+}
+
+pub fn doctor(db: &ImageDrive, prune: bool) {
+    match db.doctor(prune) {
+        Err(e) => println!("doctor fail: {:?}", e),
+        Ok(report) => {
+            if report.orphans.is_empty() {
+                println!("no orphaned containers");
+            }
+            for id in &report.orphans {
+                if report.removed.contains(id) {
+                    println!("removed orphaned container: {}", id);
+                } else {
+                    println!("orphaned container: {}", id);
+                }
+            }
+            if !prune && !report.orphans.is_empty() {
+                println!("run `doctor --prune` to remove them");
+            }
+            for id in &report.unlabeled {
+                println!(
+                    "container {} is created by an old imagedrive, \
+                     remove it by hand after checking it has no unsynced data",
+                    id
+                );
+            }
+        }
+    }
+}
+
+pub fn fsck(db: &ImageDrive, repair: bool) -> bool {
+    match db.fsck(repair) {
+        Err(e) => {
+            println!("fsck fail: {:?}", e);
+            false
+        }
+        Ok(report) => {
+            for key in &report.missing {
+                println!("missing checksum: {}", key);
+            }
+            for key in &report.orphaned {
+                println!("orphaned checksum: {}", key);
+            }
+            for key in &report.corrupt {
+                println!("corrupt item: {}", key);
+            }
+            for key in &report.repaired {
+                println!("repaired: {}", key);
+            }
+            let problems = report.missing.len() + report.orphaned.len() + report.corrupt.len();
+            println!("{} items checked, {} problems", report.checked, problems);
+            if !repair && report.missing.len() + report.orphaned.len() > 0 {
+                println!("run `fsck --repair` to rebuild checksums");
+            }
+            if !report.corrupt.is_empty() {
+                println!("restore corrupt items with `checkout` or `revert`");
+            }
+            true
+        }
+    }
+}
+
+fn format_time(time: u64) -> String {
+    Local
+        .timestamp_opt(time as i64, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "<unknown>".to_owned())
+}
+
+fn print_changes(changes: &[Change]) {
+    for change in changes {
+        match change {
+            Change::Added(item) => println!("\tadded:    {}", item),
+            Change::Modified(item) => println!("\tmodified: {}", item),
+            Change::Deleted(item) => println!("\tdeleted:  {}", item),
+        }
+    }
+}
+
+pub fn log(db: &ImageDrive) {
+    match db.log() {
+        Err(e) => println!("log fail: {:?}", e),
+        Ok(revs) => {
+            let mut table = Table::new();
+            table.add_row(row!["Rev", "Time", "Added", "Modified", "Deleted", "Message"]);
+            for r in revs.iter().rev() {
+                table.add_row(row![
+                    r.rev,
+                    format_time(r.time),
+                    r.added,
+                    r.modified,
+                    r.deleted,
+                    r.message
+                ]);
+            }
+            table.printstd();
+        }
+    }
+}
+
+pub fn show(db: &ImageDrive, rev: u32) {
+    match db.show(rev) {
+        Err(e) => println!("show revision fail: {:?}", e),
+        Ok((r, changes)) => {
+            print_revision(&r);
+            print_changes(&changes);
+        }
+    }
+}
+
+fn print_revision(r: &Revision) {
+    println!("revision {}", r.rev);
+    println!("Date: {}", format_time(r.time));
+    println!("\n\t{}\n", r.message);
+}
+
+pub fn checkout(db: &ImageDrive, rev: u32, force: bool) -> bool {
+    match db.checkout(rev, force) {
+        Err(e) => {
+            println!("checkout fail: {:?}", e);
+            false
+        }
+        Ok(_) => {
+            println!(
+                "localDB is the same as revision {} now, sync to commit it",
+                rev
+            );
+            true
+        }
+    }
+}
+
+pub fn revert(db: &ImageDrive, rev: u32, force: bool) -> bool {
+    match db.revert(rev, force) {
+        Err(e) => {
+            println!("revert fail: {:?}", e);
+            false
+        }
+        Ok(changes) => {
+            println!("reverted revision {}, sync to commit it:", rev);
+            print_changes(&changes);
+            true
+        }
+    }
+}
+
+pub fn snapshot_create(db: &ImageDrive, name: &str) {
+    match db.snapshot_create(name) {
+        Err(e) => println!("create snapshot fail: {:?}", e),
+        Ok(_) => println!("snapshot '{}' created", name),
+    }
+}
+
+pub fn snapshot_list(db: &ImageDrive) {
+    match db.snapshots() {
+        Err(e) => println!("list snapshots fail: {:?}", e),
+        Ok(snapshots) => {
+            let mut table = Table::new();
+            table.add_row(row!["Snapshot", "Created"]);
+            for s in snapshots {
+                table.add_row(row![s.name, s.created]);
+            }
+            table.printstd();
+        }
+    }
+}
+
+pub fn snapshot_restore(db: &ImageDrive, name: &str, force: bool) -> bool {
+    match db.snapshot_restore(name, force) {
+        Err(e) => {
+            println!("restore snapshot fail: {:?}", e);
+            false
+        }
+        Ok(_) => {
+            println!(
+                "localDB is the same as snapshot '{}' now, sync to commit it",
+                name
+            );
+            true
+        }
+    }
+}
+
+pub fn trash_list(db: &ImageDrive) {
+    match db.trash() {
+        Err(e) => println!("list trash fail: {:?}", e),
+        Ok(items) => {
+            let mut table = Table::new();
+            table.add_row(row!["Item", "Deleted"]);
+            for i in items {
+                table.add_row(row![
+                    format!("{}/{}", i.entry, i.item),
+                    format_time(i.deleted)
+                ]);
+            }
+            table.printstd();
+        }
+    }
+}
+
+pub fn trash_restore(db: &ImageDrive, entry: &str, item: &str) -> bool {
+    match db.trash_restore(entry, item) {
+        Err(e) => {
+            println!("restore from trash fail: {:?}", e);
+            false
+        }
+        Ok(_) => {
+            println!("restored {}/{}", entry, item);
+            true
+        }
+    }
+}
+
+pub fn trash_empty(db: &ImageDrive, older_than: Option<u64>) -> bool {
+    match db.trash_empty(older_than) {
+        Err(e) => {
+            println!("empty trash fail: {:?}", e);
+            false
+        }
+        Ok(n) => {
+            println!("removed {} items from trash", n);
+            true
+        }
     }
 }
 
@@ -74,4 +517,11 @@ mod tests {
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn format_size() {
+        assert_eq!(super::format_size(None), "<unknown>");
+        assert_eq!(super::format_size(Some(1000)), "1000 B");
+        assert_eq!(super::format_size(Some(1536)), "1.5 KiB");
+    }
 }
@@ -0,0 +1,909 @@
+use db::{Conflict, ItemInfo, DB};
+use fuser::consts::FOPEN_DIRECT_IO;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+use libc::{c_int, EIO, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EROFS, EXDEV};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fmt::{Debug, Display};
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// attributes may change by other imagedrive commands, so they are kept briefly
+const TTL: Duration = Duration::from_secs(1);
+
+/// Node is what an inode stands for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    Root,
+    Entry(String),
+    Item(String, String),
+}
+
+/// Inodes number entries and items for the kernel, a node keeps its number while mounted
+struct Inodes {
+    nodes: Vec<Node>,
+    inos: HashMap<Node, u64>,
+}
+
+impl Inodes {
+    fn new() -> Inodes {
+        let mut inodes = Inodes {
+            nodes: vec![],
+            inos: HashMap::new(),
+        };
+        inodes.ino(Node::Root);
+        inodes
+    }
+
+    fn ino(&mut self, node: Node) -> u64 {
+        if let Some(ino) = self.inos.get(&node) {
+            return *ino;
+        }
+        self.nodes.push(node.clone());
+        let ino = self.nodes.len() as u64;
+        self.inos.insert(node, ino);
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get((ino as usize).checked_sub(1)?)
+    }
+
+    /// rename `from` to `to`, which takes over its number
+    fn rename(&mut self, from: &Node, to: Node) {
+        if let Some(ino) = self.inos.remove(from) {
+            self.inos.insert(to.clone(), ino);
+            self.nodes[ino as usize - 1] = to;
+        }
+    }
+}
+
+/// Handle is an open item, its content is spooled to a temp file on the first read or write,
+/// so opening doesn't wait for the whole item
+struct Handle {
+    ino: u64,
+    file: File,
+    /// the content is in `file`
+    loaded: bool,
+    /// written since it was last staged
+    dirty: bool,
+}
+
+struct DriveFs<'a, E, D> {
+    db: &'a D,
+    writable: bool,
+    inodes: Inodes,
+    /// items of each entry as last listed
+    items: HashMap<String, Vec<ItemInfo>>,
+    /// entries made by mkdir, which stay empty until an item is written
+    new_entries: BTreeSet<String>,
+    handles: HashMap<u64, Handle>,
+    next_fh: u64,
+    uid: u32,
+    gid: u32,
+    mounted: SystemTime,
+    error: PhantomData<E>,
+}
+
+/// mount `db` at `mountpoint` until it is unmounted, entries are directories and items files.
+/// Writes are staged into the local drive when a file is closed, `sync` commits them
+pub fn mount<E, D>(db: &D, mountpoint: &Path, writable: bool) -> io::Result<()>
+where
+    E: Debug + Display,
+    D: DB<E>,
+{
+    let fs = drive_fs(db, mountpoint, writable)?;
+    println!(
+        "mounted at {}, unmount with `fusermount -u {}`",
+        mountpoint.display(),
+        mountpoint.display()
+    );
+    fuser::mount2(fs, mountpoint, &options(writable))
+}
+
+fn drive_fs<'a, E, D>(db: &'a D, mountpoint: &Path, writable: bool) -> io::Result<DriveFs<'a, E, D>>
+where
+    E: Debug + Display,
+    D: DB<E>,
+{
+    let meta = std::fs::metadata(mountpoint)?;
+    Ok(DriveFs {
+        db,
+        writable,
+        inodes: Inodes::new(),
+        items: HashMap::new(),
+        new_entries: BTreeSet::new(),
+        handles: HashMap::new(),
+        next_fh: 1,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        mounted: SystemTime::now(),
+        error: PhantomData,
+    })
+}
+
+fn options(writable: bool) -> Vec<MountOption> {
+    let mut options = vec![
+        MountOption::FSName("imagedrive".to_owned()),
+        MountOption::DefaultPermissions,
+    ];
+    options.push(if writable {
+        MountOption::RW
+    } else {
+        MountOption::RO
+    });
+    options
+}
+
+impl<'a, E, D> DriveFs<'a, E, D>
+where
+    E: Debug + Display,
+    D: DB<E>,
+{
+    fn failed(&self, op: &str, err: E) -> c_int {
+        println!("{} failed: {}", op, err);
+        EIO
+    }
+
+    fn entries(&self) -> Result<Vec<String>, c_int> {
+        let mut entries = self
+            .db
+            .entries()
+            .map_err(|e| self.failed("list entries", e))?;
+        for entry in &self.new_entries {
+            if !entries.contains(entry) {
+                entries.push(entry.clone());
+            }
+        }
+        Ok(entries)
+    }
+
+    fn has_entry(&self, entry: &str) -> Result<bool, c_int> {
+        Ok(self.items.contains_key(entry) || self.entries()?.iter().any(|e| e == entry))
+    }
+
+    /// items of `entry`, listed again if `reload`
+    fn items(&mut self, entry: &str, reload: bool) -> Result<&mut Vec<ItemInfo>, c_int> {
+        if reload || !self.items.contains_key(entry) {
+            let items = if self.new_entries.contains(entry) {
+                vec![]
+            } else {
+                self.db
+                    .item_infos(entry)
+                    .map_err(|e| self.failed("list items", e))?
+            };
+            self.items.insert(entry.to_owned(), items);
+        }
+        Ok(self.items.get_mut(entry).unwrap())
+    }
+
+    fn attr(&self, ino: u64, kind: FileType, size: u64) -> FileAttr {
+        let perm = match (kind, self.writable) {
+            (FileType::Directory, true) => 0o755,
+            (FileType::Directory, false) => 0o555,
+            (_, true) => 0o644,
+            (_, false) => 0o444,
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mounted,
+            mtime: self.mounted,
+            ctime: self.mounted,
+            crtime: self.mounted,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    fn node_attr(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        // an open item may be bigger than listed
+        if let Some(h) = self.handles.values().find(|h| h.ino == ino && h.loaded) {
+            let size = h.file.metadata().map_err(|_| EIO)?.len();
+            return Ok(self.attr(ino, FileType::RegularFile, size));
+        }
+        match self.inodes.node(ino).cloned() {
+            Some(Node::Root) | Some(Node::Entry(_)) => Ok(self.attr(ino, FileType::Directory, 0)),
+            Some(Node::Item(entry, item)) => {
+                let size = self
+                    .items(&entry, false)?
+                    .iter()
+                    .find(|i| i.name == item)
+                    .ok_or(ENOENT)?
+                    .size
+                    .unwrap_or(0);
+                Ok(self.attr(ino, FileType::RegularFile, size))
+            }
+            None => Err(ENOENT),
+        }
+    }
+
+    fn entry_of(&self, parent: u64) -> Result<String, c_int> {
+        match self.inodes.node(parent) {
+            Some(Node::Entry(entry)) => Ok(entry.clone()),
+            Some(_) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn lookup_node(&mut self, parent: u64, name: &str) -> Result<FileAttr, c_int> {
+        match self.inodes.node(parent).cloned() {
+            Some(Node::Root) => {
+                if !self.has_entry(name)? {
+                    return Err(ENOENT);
+                }
+                let ino = self.inodes.ino(Node::Entry(name.to_owned()));
+                Ok(self.attr(ino, FileType::Directory, 0))
+            }
+            Some(Node::Entry(entry)) => {
+                // directory items can't be read, so they are hidden
+                let file = |i: &ItemInfo| i.name == name && !i.dir;
+                let found = self.items(&entry, false)?.iter().any(file)
+                    || self.items(&entry, true)?.iter().any(file);
+                if !found {
+                    return Err(ENOENT);
+                }
+                let ino = self.inodes.ino(Node::Item(entry, name.to_owned()));
+                self.node_attr(ino)
+            }
+            Some(Node::Item(..)) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn list(&mut self, ino: u64) -> Result<Vec<(u64, FileType, String)>, c_int> {
+        let mut list = vec![
+            (ino, FileType::Directory, ".".to_owned()),
+            (FUSE_ROOT_ID, FileType::Directory, "..".to_owned()),
+        ];
+        match self.inodes.node(ino).cloned() {
+            Some(Node::Root) => {
+                self.items.clear();
+                for entry in self.entries()? {
+                    let ino = self.inodes.ino(Node::Entry(entry.clone()));
+                    list.push((ino, FileType::Directory, entry));
+                }
+            }
+            Some(Node::Entry(entry)) => {
+                let names: Vec<String> = self
+                    .items(&entry, true)?
+                    .iter()
+                    .filter(|i| !i.dir)
+                    .map(|i| i.name.clone())
+                    .collect();
+                for name in names {
+                    let ino = self.inodes.ino(Node::Item(entry.clone(), name.clone()));
+                    list.push((ino, FileType::RegularFile, name));
+                }
+            }
+            Some(Node::Item(..)) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        }
+        Ok(list)
+    }
+
+    /// open item `ino`, empty if `truncate`, otherwise its content is loaded when needed
+    fn open_item(&mut self, ino: u64, truncate: bool) -> Result<u64, c_int> {
+        match self.inodes.node(ino) {
+            Some(Node::Item(..)) => (),
+            Some(_) => return Err(EISDIR),
+            None => return Err(ENOENT),
+        }
+        let file = tempfile::tempfile().map_err(|_| EIO)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(
+            fh,
+            Handle {
+                ino,
+                file,
+                loaded: truncate,
+                dirty: truncate,
+            },
+        );
+        Ok(fh)
+    }
+
+    /// load the content of handle `fh` from the drive, unless it is loaded
+    fn load(&mut self, fh: u64) -> Result<&mut Handle, c_int> {
+        let ino = match self.handles.get(&fh) {
+            Some(h) if h.loaded => return Ok(self.handles.get_mut(&fh).unwrap()),
+            Some(h) => h.ino,
+            None => return Err(libc::EBADF),
+        };
+        let (entry, item) = match self.inodes.node(ino) {
+            Some(Node::Item(entry, item)) => (entry.clone(), item.clone()),
+            _ => return Err(ENOENT),
+        };
+        let mut file = self.handles[&fh].file.try_clone().map_err(|_| EIO)?;
+        self.db
+            .cat(&entry, &item, &mut file)
+            .map_err(|e| self.failed(&format!("read {}/{}", entry, item), e))?;
+        let h = self.handles.get_mut(&fh).unwrap();
+        h.loaded = true;
+        Ok(h)
+    }
+
+    /// stage the content of handle `fh` into the local drive, if it was written
+    fn stage(&mut self, fh: u64) -> Result<(), c_int> {
+        let ino = match self.handles.get(&fh) {
+            Some(h) if h.dirty => h.ino,
+            _ => return Ok(()),
+        };
+        let (entry, item) = match self.inodes.node(ino) {
+            Some(Node::Item(entry, item)) => (entry.clone(), item.clone()),
+            _ => return Err(ENOENT),
+        };
+        // `add` reads a path, so the content is copied out of the handle
+        let dir = tempfile::tempdir().map_err(|_| EIO)?;
+        let path = dir.path().join(&item);
+        {
+            let h = self.handles.get_mut(&fh).unwrap();
+            let mut dst = File::create(&path).map_err(|_| EIO)?;
+            let mut src = h.file.try_clone().map_err(|_| EIO)?;
+            io::Seek::seek(&mut src, io::SeekFrom::Start(0)).map_err(|_| EIO)?;
+            io::copy(&mut src, &mut dst).map_err(|_| EIO)?;
+        }
+        self.db
+            .add(&entry, &path, Some(&item), Conflict::Overwrite)
+            .map_err(|e| self.failed(&format!("write {}/{}", entry, item), e))?;
+        self.new_entries.remove(&entry);
+        self.handles.get_mut(&fh).unwrap().dirty = false;
+        self.items(&entry, true)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, parent: u64, name: &str) -> Result<(), c_int> {
+        let entry = self.entry_of(parent)?;
+        if !self.items(&entry, false)?.iter().any(|i| i.name == name) {
+            return Err(ENOENT);
+        }
+        self.db
+            .delete(&entry, Some(name))
+            .map_err(|e| self.failed(&format!("delete {}/{}", entry, name), e))?;
+        self.items(&entry, false)?.retain(|i| i.name != name);
+        Ok(())
+    }
+
+    fn remove_entry(&mut self, name: &str) -> Result<(), c_int> {
+        if self.new_entries.remove(name) {
+            self.items.remove(name);
+            return Ok(());
+        }
+        if !self.has_entry(name)? {
+            return Err(ENOENT);
+        }
+        if !self.items(name, true)?.is_empty() {
+            return Err(ENOTEMPTY);
+        }
+        self.db
+            .delete(name, None)
+            .map_err(|e| self.failed(&format!("delete {}", name), e))?;
+        self.items.remove(name);
+        Ok(())
+    }
+
+    fn rename_item(
+        &mut self,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+    ) -> Result<(), c_int> {
+        // moving between entries is copying, which tools do themselves on EXDEV
+        if parent != newparent {
+            return Err(EXDEV);
+        }
+        let entry = self.entry_of(parent)?;
+        let items = self.items(&entry, true)?;
+        if !items.iter().any(|i| i.name == name) {
+            return Err(ENOENT);
+        }
+        // like rename(2), an existing item of the new name is replaced
+        if items.iter().any(|i| i.name == newname) {
+            self.remove(parent, newname)?;
+        }
+        self.db
+            .rename(&entry, name, newname)
+            .map_err(|e| self.failed(&format!("rename {}/{}", entry, name), e))?;
+        self.inodes.rename(
+            &Node::Item(entry.clone(), name.to_owned()),
+            Node::Item(entry.clone(), newname.to_owned()),
+        );
+        self.items(&entry, true)?;
+        Ok(())
+    }
+}
+
+impl<'a, E, D> Filesystem for DriveFs<'a, E, D>
+where
+    E: Debug + Display,
+    D: DB<E>,
+{
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_node(parent, &name.to_string_lossy()) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.node_attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // only truncation is kept, items have no modes or times of their own
+        let res = match size {
+            Some(_) if !self.writable => Err(EROFS),
+            Some(size) => {
+                let opened = fh.filter(|fh| self.handles.contains_key(fh));
+                let fh = match opened {
+                    Some(fh) => Ok(fh),
+                    None => self.open_item(ino, size == 0),
+                };
+                fh.and_then(|fh| {
+                    // content past `size` is dropped, so it isn't loaded for a truncation to 0
+                    if size == 0 {
+                        self.handles.get_mut(&fh).unwrap().loaded = true;
+                    }
+                    let h = self.load(fh)?;
+                    h.file.set_len(size).map_err(|_| EIO)?;
+                    h.dirty = true;
+                    if opened.is_none() {
+                        let res = self.stage(fh);
+                        self.handles.remove(&fh);
+                        res?;
+                    }
+                    self.node_attr(ino)
+                })
+            }
+            None => self.node_attr(ino),
+        };
+        match res {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        if !self.writable {
+            return reply.error(EROFS);
+        }
+        if parent != FUSE_ROOT_ID {
+            // entries hold items only
+            return reply.error(libc::EPERM);
+        }
+        let name = name.to_string_lossy().to_string();
+        self.new_entries.insert(name.clone());
+        let ino = self.inodes.ino(Node::Entry(name));
+        reply.entry(&TTL, &self.attr(ino, FileType::Directory, 0), 0);
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if !self.writable {
+            return reply.error(EROFS);
+        }
+        match self.remove(parent, &name.to_string_lossy()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if !self.writable {
+            return reply.error(EROFS);
+        }
+        if parent != FUSE_ROOT_ID {
+            return reply.error(ENOTDIR);
+        }
+        match self.remove_entry(&name.to_string_lossy()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        if !self.writable {
+            return reply.error(EROFS);
+        }
+        if parent == FUSE_ROOT_ID {
+            return reply.error(EXDEV);
+        }
+        match self.rename_item(
+            parent,
+            &name.to_string_lossy(),
+            newparent,
+            &newname.to_string_lossy(),
+        ) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let write = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        if write && !self.writable {
+            return reply.error(EROFS);
+        }
+        // sizes of items added by old imagedrive are unknown, so reads skip the page cache
+        let unknown = match self.inodes.node(ino).cloned() {
+            Some(Node::Item(entry, item)) => self
+                .items(&entry, false)
+                .map(|items| items.iter().any(|i| i.name == item && i.size.is_none()))
+                .unwrap_or(false),
+            _ => false,
+        };
+        match self.open_item(ino, write && flags & libc::O_TRUNC != 0) {
+            Ok(fh) => reply.opened(fh, if unknown { FOPEN_DIRECT_IO } else { 0 }),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        if !self.writable {
+            return reply.error(EROFS);
+        }
+        let entry = match self.entry_of(parent) {
+            Ok(entry) => entry,
+            Err(e) => return reply.error(e),
+        };
+        let name = name.to_string_lossy().to_string();
+        if let Ok(items) = self.items(&entry, false) {
+            // a hidden directory item would be overwritten
+            if items.iter().any(|i| i.name == name && i.dir) {
+                return reply.error(libc::EEXIST);
+            }
+            if !items.iter().any(|i| i.name == name) {
+                items.push(ItemInfo {
+                    name: name.clone(),
+                    checksum: None,
+                    size: Some(0),
+                    stored: None,
+                    dir: false,
+                });
+            }
+        }
+        let ino = self.inodes.ino(Node::Item(entry, name));
+        match self.open_item(ino, true) {
+            Ok(fh) => reply.created(&TTL, &self.attr(ino, FileType::RegularFile, 0), 0, fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let h = match self.load(fh) {
+            Ok(h) => h,
+            Err(e) => return reply.error(e),
+        };
+        let mut buf = vec![0; size as usize];
+        let mut n = 0;
+        while n < buf.len() {
+            match h.file.read_at(&mut buf[n..], offset as u64 + n as u64) {
+                Ok(0) => break,
+                Ok(read) => n += read,
+                Err(_) => return reply.error(EIO),
+            }
+        }
+        reply.data(&buf[..n]);
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let h = match self.load(fh) {
+            Ok(h) => h,
+            Err(e) => return reply.error(e),
+        };
+        match h.file.write_all_at(data, offset as u64) {
+            Ok(()) => {
+                h.dirty = true;
+                reply.written(data.len() as u32);
+            }
+            Err(_) => reply.error(EIO),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        // staged on close, so close reports a failed write
+        match self.stage(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.stage(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let res = self.stage(fh);
+        self.handles.remove(&fh);
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let list = match self.list(ino) {
+            Ok(list) => list,
+            Err(e) => return reply.error(e),
+        };
+        for (i, (ino, kind, name)) in list.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inodes() {
+        let mut inodes = Inodes::new();
+        assert_eq!(inodes.ino(Node::Root), FUSE_ROOT_ID);
+        let e = inodes.ino(Node::Entry("e".to_owned()));
+        let a = inodes.ino(Node::Item("e".to_owned(), "a".to_owned()));
+        assert_ne!(e, a);
+        assert_eq!(inodes.ino(Node::Entry("e".to_owned())), e);
+        let b = Node::Item("e".to_owned(), "b".to_owned());
+        inodes.rename(&Node::Item("e".to_owned(), "a".to_owned()), b.clone());
+        assert_eq!(inodes.ino(b.clone()), a);
+        assert_eq!(inodes.node(a), Some(&b));
+        assert_eq!(inodes.node(0), None);
+        assert_eq!(inodes.node(100), None);
+    }
+
+    /// FakeDb is entry `e` with file item `a` and directory item `d`, counting reads
+    struct FakeDb {
+        cats: std::sync::atomic::AtomicUsize,
+    }
+
+    impl DB<String> for FakeDb {
+        fn entries(&self) -> Result<Vec<String>, String> {
+            Ok(vec!["e".to_owned()])
+        }
+        fn items(&self, _entry: &str) -> Result<Vec<String>, String> {
+            Ok(vec!["a".to_owned(), "d".to_owned()])
+        }
+        fn item_infos(&self, _entry: &str) -> Result<Vec<ItemInfo>, String> {
+            let info = |name: &str, size, dir| ItemInfo {
+                name: name.to_owned(),
+                checksum: None,
+                size: Some(size),
+                stored: None,
+                dir,
+            };
+            Ok(vec![info("a", 5, false), info("d", 3, true)])
+        }
+        fn add(
+            &self,
+            _: &str,
+            _: &Path,
+            _: Option<&str>,
+            _: Conflict,
+        ) -> Result<db::AddResult, String> {
+            Err("read-only".to_owned())
+        }
+        fn add_many(
+            &self,
+            _: &str,
+            _: &[&Path],
+            _: Conflict,
+        ) -> Result<Vec<(std::path::PathBuf, Result<db::AddResult, String>)>, String> {
+            Err("read-only".to_owned())
+        }
+        fn mirror_push(&self, _: &Path, _: &str, _: bool) -> Result<db::MirrorReport, String> {
+            Err("read-only".to_owned())
+        }
+        fn mirror_pull(&self, _: &str, _: &Path, _: bool) -> Result<db::MirrorReport, String> {
+            Err("read-only".to_owned())
+        }
+        fn delete(&self, _: &str, _: Option<&str>) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn rename(&self, _: &str, _: &str, _: &str) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn export_to_dir(&self, _: &Path, _: &str) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn cat(&self, entry: &str, item: &str, out: &mut dyn io::Write) -> Result<(), String> {
+            self.cats.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            match (entry, item) {
+                ("e", "a") => out.write_all(b"hello").map_err(|e| e.to_string()),
+                _ => Err(format!("cat {}/{}: is a directory", entry, item)),
+            }
+        }
+        fn sync(&self, _: Option<&str>) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn sync_from_remote(&self) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn status(&self) -> Result<db::Status, String> {
+            Err("unsupported".to_owned())
+        }
+        fn log(&self) -> Result<Vec<db::Revision>, String> {
+            Ok(vec![])
+        }
+        fn show(&self, rev: u32) -> Result<(db::Revision, Vec<db::Change>), String> {
+            Err(format!("no revision {}", rev))
+        }
+        fn checkout(&self, _: u32, _: bool) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn revert(&self, _: u32, _: bool) -> Result<Vec<db::Change>, String> {
+            Err("read-only".to_owned())
+        }
+        fn snapshot_create(&self, _: &str) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn snapshots(&self) -> Result<Vec<db::Snapshot>, String> {
+            Ok(vec![])
+        }
+        fn snapshot_restore(&self, _: &str, _: bool) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn trash(&self) -> Result<Vec<db::TrashItem>, String> {
+            Ok(vec![])
+        }
+        fn trash_restore(&self, _: &str, _: &str) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+        fn trash_empty(&self, _: Option<u64>) -> Result<usize, String> {
+            Ok(0)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mount_reads_lazily_and_hides_directories() {
+        if !Path::new("/dev/fuse").exists() {
+            println!("skipped, /dev/fuse is absent");
+            return;
+        }
+        let db: &'static FakeDb = Box::leak(Box::new(FakeDb {
+            cats: std::sync::atomic::AtomicUsize::new(0),
+        }));
+        let cats = || db.cats.load(std::sync::atomic::Ordering::SeqCst);
+        let dir = tempfile::tempdir().unwrap();
+        let fs = drive_fs(db, dir.path(), false).unwrap();
+        let session = fuser::spawn_mount2(fs, dir.path(), &options(false)).unwrap();
+
+        let entry = dir.path().join("e");
+        let mut names: Vec<String> = std::fs::read_dir(&entry)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a"]);
+        assert!(!entry.join("d").exists());
+
+        let mut file = File::open(entry.join("a")).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 5);
+        assert_eq!(cats(), 0);
+        let mut content = String::new();
+        io::Read::read_to_string(&mut file, &mut content).unwrap();
+        assert_eq!(content, "hello");
+        assert_eq!(cats(), 1);
+        drop(file);
+        session.join();
+    }
+}
@@ -0,0 +1,81 @@
+use dockerclient::Progress;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Bars draw a progress bar per copy and a spinner per layer of a push or pull,
+/// each one is left with its statistics when it finishes.
+/// Layers have no byte counts, so their spinners show the status docker prints
+pub struct Bars {
+    multi: MultiProgress,
+    bars: Mutex<HashMap<String, (ProgressBar, Instant)>>,
+}
+
+/// progress return the renderer of transfers, None if `quiet` or stdout is not a terminal
+pub fn progress(quiet: bool) -> Option<Arc<dyn Progress>> {
+    if quiet || !std::io::stdout().is_terminal() {
+        return None;
+    }
+    Some(Arc::new(Bars {
+        multi: MultiProgress::new(),
+        bars: Mutex::new(HashMap::new()),
+    }))
+}
+
+impl Progress for Bars {
+    fn start(&self, task: &str, total: Option<u64>) {
+        let bar = match total {
+            Some(total) => ProgressBar::new(total).with_style(
+                ProgressStyle::with_template(
+                    "{msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} eta {eta}",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner()
+                .with_style(ProgressStyle::with_template("{spinner} {msg}").unwrap()),
+        };
+        bar.set_message(task.to_owned());
+        let bar = self.multi.add(bar);
+        self.bars
+            .lock()
+            .unwrap()
+            .insert(task.to_owned(), (bar, Instant::now()));
+    }
+
+    fn update(&self, task: &str, done: u64) {
+        if let Some((bar, _)) = self.bars.lock().unwrap().get(task) {
+            bar.set_position(done);
+            if bar.length().is_none() {
+                bar.set_message(format!("{} {}", task, HumanBytes(done)));
+            }
+        }
+    }
+
+    fn status(&self, task: &str, status: &str) {
+        if let Some((bar, _)) = self.bars.lock().unwrap().get(task) {
+            bar.set_message(format!("{} {}", task, status));
+            bar.tick();
+        }
+    }
+
+    fn finish(&self, task: &str) {
+        if let Some((bar, started)) = self.bars.lock().unwrap().remove(task) {
+            let secs = started.elapsed().as_secs_f64();
+            let done = bar.position();
+            if done == 0 {
+                bar.finish();
+                return;
+            }
+            bar.finish_with_message(format!(
+                "{} {} in {:.1}s ({}/s)",
+                task,
+                HumanBytes(done),
+                secs,
+                HumanBytes((done as f64 / secs.max(0.001)) as u64)
+            ));
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use db::{AddResult, Conflict, DB};
+use serde_json::{json, Value};
+use std::fmt::{Debug, Display};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Reply is the answer to a request before it is written
+enum Reply {
+    Json(u16, Value),
+    File(File),
+    Empty(u16),
+    Error(u16, String),
+}
+
+struct Api<'a, E, D> {
+    db: &'a D,
+    listen: &'a str,
+    token: String,
+    error: PhantomData<E>,
+}
+
+/// serve `db` over http at `listen` until killed:
+///
+/// - `GET /entries` list entries
+/// - `GET /entries/<entry>` list items with their metadata
+/// - `GET|PUT|DELETE /entries/<entry>/<item>` read, write or delete an item
+/// - `DELETE /entries/<entry>` delete an entry
+/// - `POST /sync?message=<message>` sync the drive
+///
+/// Every request needs `Authorization: Bearer <token>`, a random token is made and printed
+/// if there is none. Requests from browsers, those with an `Origin` or another `Host`,
+/// are refused, so web pages can't reach the drive
+pub fn serve<E, D>(db: &D, listen: &str, token: Option<&str>) -> io::Result<()>
+where
+    E: Debug + Display,
+    D: DB<E>,
+{
+    let token = match token {
+        Some(token) => token.to_owned(),
+        None => random_token()?,
+    };
+    let server = Server::http(listen).map_err(|e| io::Error::other(e.to_string()))?;
+    println!("serving at http://{}", listen);
+    println!("token: {}", token);
+    let api = Api {
+        db,
+        listen,
+        token,
+        error: PhantomData,
+    };
+    // requests are served one by one, as the drive is one container
+    for mut request in server.incoming_requests() {
+        let reply = api.handle(&mut request);
+        let status = match &reply {
+            Reply::Json(status, _) | Reply::Empty(status) | Reply::Error(status, _) => *status,
+            Reply::File(_) => 200,
+        };
+        println!("{} {} {}", request.method(), request.url(), status);
+        let res = match reply {
+            Reply::Json(status, value) => request.respond(
+                Response::from_string(value.to_string())
+                    .with_status_code(status)
+                    .with_header(content_type("application/json")),
+            ),
+            Reply::File(file) => request.respond(
+                Response::from_file(file).with_header(content_type("application/octet-stream")),
+            ),
+            Reply::Empty(status) => request.respond(Response::empty(status)),
+            Reply::Error(status, message) => request.respond(
+                Response::from_string(json!({ "error": message }).to_string())
+                    .with_status_code(status)
+                    .with_header(content_type("application/json")),
+            ),
+        };
+        if let Err(e) = res {
+            println!("reply failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap()
+}
+
+impl<'a, E, D> Api<'a, E, D>
+where
+    E: Debug + Display,
+    D: DB<E>,
+{
+    fn handle(&self, request: &mut Request) -> Reply {
+        if let Err(reply) = self.check(request) {
+            return reply;
+        }
+        let url = request.url().to_owned();
+        let (path, query) = match url.find('?') {
+            Some(pos) => (&url[..pos], &url[pos + 1..]),
+            None => (url.as_str(), ""),
+        };
+        let segments: Option<Vec<String>> = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(decode)
+            .collect();
+        let segments = match segments {
+            Some(segments) => segments,
+            None => return Reply::Error(400, format!("bad path: {}", path)),
+        };
+        let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+        let res = match (request.method(), segments.as_slice()) {
+            (Method::Get, ["entries"]) => self.entries(),
+            (Method::Get, ["entries", entry]) => self.items(entry),
+            (Method::Delete, ["entries", entry]) => self.delete(entry, None),
+            (Method::Get, ["entries", entry, item]) => self.get(entry, item),
+            (Method::Put, ["entries", entry, item]) => self.put(entry, item, request.as_reader()),
+            (Method::Delete, ["entries", entry, item]) => self.delete(entry, Some(item)),
+            (Method::Post, ["sync"]) => self.sync(param(query, "message").as_deref()),
+            (_, ["entries", ..]) | (_, ["sync"]) => {
+                Err(Reply::Error(405, "method not allowed".to_owned()))
+            }
+            _ => Err(Reply::Error(404, format!("not found: {}", path))),
+        };
+        res.unwrap_or_else(|reply| reply)
+    }
+
+    /// check refuse requests from browsers and those without the token
+    fn check(&self, request: &Request) -> Result<(), Reply> {
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv(name))
+                .map(|h| h.value.as_str())
+        };
+        if header("Origin").is_some() {
+            return Err(Reply::Error(403, "cross-origin request".to_owned()));
+        }
+        if !header("Host").is_some_and(|host| local_host(host, self.listen)) {
+            return Err(Reply::Error(403, "foreign host".to_owned()));
+        }
+        let bearer = header("Authorization").and_then(|a| a.strip_prefix("Bearer "));
+        if !bearer.is_some_and(|t| same_token(t, &self.token)) {
+            return Err(Reply::Error(401, "bad token".to_owned()));
+        }
+        Ok(())
+    }
+
+    fn failed(&self, err: E) -> Reply {
+        Reply::Error(500, err.to_string())
+    }
+
+    fn entries(&self) -> Result<Reply, Reply> {
+        let entries = self.db.entries().map_err(|e| self.failed(e))?;
+        Ok(Reply::Json(200, json!(entries)))
+    }
+
+    fn has_entry(&self, entry: &str) -> Result<(), Reply> {
+        valid_name(entry)?;
+        let entries = self.db.entries().map_err(|e| self.failed(e))?;
+        if entries.iter().any(|e| e == entry) {
+            Ok(())
+        } else {
+            Err(Reply::Error(404, format!("entry not found: {}", entry)))
+        }
+    }
+
+    fn has_item(&self, entry: &str, item: &str) -> Result<(), Reply> {
+        self.has_entry(entry)?;
+        valid_name(item)?;
+        let items = self.db.items(entry).map_err(|e| self.failed(e))?;
+        if items.iter().any(|i| i == item) {
+            Ok(())
+        } else {
+            Err(Reply::Error(
+                404,
+                format!("item not found: {}/{}", entry, item),
+            ))
+        }
+    }
+
+    fn items(&self, entry: &str) -> Result<Reply, Reply> {
+        self.has_entry(entry)?;
+        let items = self.db.item_infos(entry).map_err(|e| self.failed(e))?;
+        let items: Vec<Value> = items
+            .into_iter()
+            .map(|i| {
+                json!({
+                    "name": i.name,
+                    "checksum": i.checksum,
+                    "size": i.size,
+                    "stored": i.stored,
+                })
+            })
+            .collect();
+        Ok(Reply::Json(200, json!(items)))
+    }
+
+    fn get(&self, entry: &str, item: &str) -> Result<Reply, Reply> {
+        self.has_item(entry, item)?;
+        // spooled, so the length is known and a failed read is not sent half
+        let mut file = tempfile::tempfile().map_err(|e| Reply::Error(500, e.to_string()))?;
+        self.db
+            .cat(entry, item, &mut file)
+            .map_err(|e| self.failed(e))?;
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| Reply::Error(500, e.to_string()))?;
+        Ok(Reply::File(file))
+    }
+
+    fn put(&self, entry: &str, item: &str, body: &mut dyn Read) -> Result<Reply, Reply> {
+        valid_name(entry)?;
+        valid_name(item)?;
+        // `add` reads a path, so the body is saved first
+        let dir = tempfile::tempdir().map_err(|e| Reply::Error(500, e.to_string()))?;
+        let path = dir.path().join(item);
+        let mut file = File::create(&path).map_err(|e| Reply::Error(500, e.to_string()))?;
+        io::copy(body, &mut file).map_err(|e| Reply::Error(400, e.to_string()))?;
+        drop(file);
+        match self
+            .db
+            .add(entry, &path, Some(item), Conflict::Overwrite)
+            .map_err(|e| self.failed(e))?
+        {
+            AddResult::Succ | AddResult::Renamed(_) => Ok(Reply::Empty(201)),
+            AddResult::ExistedItem(_) | AddResult::Replaced { .. } => Ok(Reply::Empty(204)),
+        }
+    }
+
+    fn delete(&self, entry: &str, item: Option<&str>) -> Result<Reply, Reply> {
+        match item {
+            Some(item) => self.has_item(entry, item)?,
+            None => self.has_entry(entry)?,
+        }
+        self.db.delete(entry, item).map_err(|e| self.failed(e))?;
+        Ok(Reply::Empty(204))
+    }
+
+    fn sync(&self, message: Option<&str>) -> Result<Reply, Reply> {
+        let message = message.map(sanitize_message);
+        self.db
+            .sync(message.as_deref())
+            .map_err(|e| self.failed(e))?;
+        Ok(Reply::Empty(204))
+    }
+}
+
+/// valid_name accept entry and item names made of letters, digits, spaces and `._-+()`,
+/// anything else is refused before it reaches the drive
+fn valid_name(name: &str) -> Result<(), Reply> {
+    let ok = !name.is_empty()
+        && name.len() <= 255
+        && name != "."
+        && name != ".."
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || " ._-+()".contains(c));
+    if ok {
+        Ok(())
+    } else {
+        Err(Reply::Error(400, format!("bad name: {:?}", name)))
+    }
+}
+
+/// sanitize_message keep a sync message on one line of at most 200 chars
+fn sanitize_message(message: &str) -> String {
+    message
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(200)
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+/// local_host is true if `host`, the Host header, names the address served at `listen`.
+/// Other names are refused, e.g. a DNS rebinding the server to another domain
+fn local_host(host: &str, listen: &str) -> bool {
+    if host == listen {
+        return true;
+    }
+    match (host.rsplit_once(':'), listen.rsplit_once(':')) {
+        (Some((name, port)), Some((_, listen_port))) => {
+            ["localhost", "127.0.0.1", "[::1]"].contains(&name) && port == listen_port
+        }
+        _ => false,
+    }
+}
+
+/// same_token compare tokens in a time independent of where they differ
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// random_token is 16 random bytes in hex
+fn random_token() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// param is the decoded value of `name` in `query`
+fn param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|kv| {
+            let mut kv = kv.splitn(2, '=');
+            Some((kv.next()?, kv.next().unwrap_or("")))
+        })
+        .find(|(k, _)| *k == name)
+        .and_then(|(_, v)| decode(&v.replace('+', " ")))
+}
+
+/// decode percent-encoded `s`, None if it is malformed or not utf-8
+fn decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_urls() {
+        assert_eq!(decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(decode("%E4%BD%A0").as_deref(), Some("\u{4f60}"));
+        assert_eq!(decode("a%2"), None);
+        assert_eq!(decode("%ff"), None);
+        assert_eq!(
+            param("x=1&message=fix+data%21", "message").as_deref(),
+            Some("fix data!")
+        );
+        assert_eq!(param("x=1", "message"), None);
+    }
+
+    #[test]
+    fn names_are_allowlisted() {
+        assert!(valid_name("report (1).pdf").is_ok());
+        assert!(valid_name("\u{4f60}\u{597d}").is_ok());
+        for bad in &[
+            "", "..", "-rf", "a/b", "a;b", "$(id)", "a`b`", "a\"b", "a'b", "a\nb",
+        ] {
+            assert!(valid_name(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn only_local_hosts() {
+        assert!(local_host("127.0.0.1:8080", "127.0.0.1:8080"));
+        assert!(local_host("localhost:8080", "127.0.0.1:8080"));
+        assert!(!local_host("localhost:9090", "127.0.0.1:8080"));
+        assert!(!local_host("evil.example:8080", "127.0.0.1:8080"));
+        assert!(same_token("abc", "abc"));
+        assert!(!same_token("abc", "abd"));
+        assert!(!same_token("abc", "ab"));
+    }
+
+    #[test]
+    fn messages_stay_on_one_line() {
+        assert_eq!(sanitize_message("fix\n\"data\"\t"), "fix \"data\"");
+        assert_eq!(sanitize_message(&"x".repeat(300)).len(), 200);
+    }
+}
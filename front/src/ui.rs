@@ -0,0 +1,454 @@
+use db::{ItemInfo, DB};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::widgets::{Block, List, ListState, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::fmt::{Debug, Display};
+use std::io;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::Path;
+
+/// bytes of an item read for its preview
+const PREVIEW_MAX: usize = 64 * 1024;
+
+const HELP: &str = "q quit  tab switch  enter open  e export  d delete  r rename  s sync  g reload";
+
+#[derive(PartialEq)]
+enum Pane {
+    Entries,
+    Items,
+}
+
+/// Prompt is a question in the status line, answered with enter
+enum Prompt {
+    Export(String),
+    Rename(String),
+    Sync(String),
+    Delete,
+}
+
+struct App<'a, E, D> {
+    db: &'a D,
+    entries: Vec<String>,
+    entry_state: ListState,
+    items: Vec<ItemInfo>,
+    item_state: TableState,
+    pane: Pane,
+    preview: String,
+    prompt: Option<Prompt>,
+    message: String,
+    quit: bool,
+    error: PhantomData<E>,
+}
+
+/// ui browse the drive `db` in the terminal, it works for any `DB` backend
+pub fn ui<E, D>(db: &D) -> io::Result<()>
+where
+    E: Debug + Display,
+    D: DB<E>,
+{
+    let mut app = App {
+        db,
+        entries: vec![],
+        entry_state: ListState::default(),
+        items: vec![],
+        item_state: TableState::default(),
+        pane: Pane::Entries,
+        preview: String::new(),
+        prompt: None,
+        message: HELP.to_owned(),
+        quit: false,
+        error: PhantomData,
+    };
+    app.reload();
+    let mut terminal = ratatui::init();
+    let res = app.run(&mut terminal);
+    ratatui::restore();
+    res
+}
+
+impl<'a, E, D> App<'a, E, D>
+where
+    E: Debug + Display,
+    D: DB<E>,
+{
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.quit {
+            terminal.draw(|f| self.draw(f))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.key(key.code);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn entry(&self) -> Option<&str> {
+        self.entry_state
+            .selected()
+            .and_then(|i| self.entries.get(i))
+            .map(|e| e.as_str())
+    }
+
+    fn item(&self) -> Option<&ItemInfo> {
+        self.item_state.selected().and_then(|i| self.items.get(i))
+    }
+
+    fn fail(&mut self, err: E) {
+        self.message = format!("error: {}", err);
+    }
+
+    /// reload entries, keeping the selection where it can
+    fn reload(&mut self) {
+        match self.db.entries() {
+            Ok(entries) => self.entries = entries,
+            Err(e) => return self.fail(e),
+        }
+        let i = self.entry_state.selected().unwrap_or(0);
+        self.entry_state.select(if self.entries.is_empty() {
+            None
+        } else {
+            Some(i.min(self.entries.len() - 1))
+        });
+        self.load_items();
+    }
+
+    fn load_items(&mut self) {
+        self.items.clear();
+        self.preview.clear();
+        if let Some(entry) = self.entry().map(|e| e.to_owned()) {
+            match self.db.item_infos(&entry) {
+                Ok(items) => self.items = items,
+                Err(e) => self.fail(e),
+            }
+        }
+        let i = self.item_state.selected().unwrap_or(0);
+        self.item_state.select(if self.items.is_empty() {
+            None
+        } else {
+            Some(i.min(self.items.len() - 1))
+        });
+    }
+
+    fn load_preview(&mut self) {
+        let (entry, item) = match (self.entry(), self.item()) {
+            (Some(entry), Some(item)) => (entry.to_owned(), item.name.clone()),
+            _ => return,
+        };
+        let mut out = Limited(Vec::new());
+        // the limit fails cat once enough is read, which is what a preview needs
+        match self.db.cat(&entry, &item, &mut out) {
+            Err(e) if out.0.len() < PREVIEW_MAX => self.fail(e),
+            _ => self.preview = preview_text(&out.0),
+        }
+    }
+
+    fn select(&mut self, down: bool) {
+        let step = |i: Option<usize>, len: usize| match (i, len) {
+            (_, 0) => None,
+            (None, _) => Some(0),
+            (Some(i), _) if down => Some((i + 1).min(len - 1)),
+            (Some(i), _) => Some(i.saturating_sub(1)),
+        };
+        match self.pane {
+            Pane::Entries => {
+                let i = step(self.entry_state.selected(), self.entries.len());
+                if i != self.entry_state.selected() {
+                    self.entry_state.select(i);
+                    self.item_state.select(None);
+                    self.load_items();
+                }
+            }
+            Pane::Items => {
+                let i = step(self.item_state.selected(), self.items.len());
+                if i != self.item_state.selected() {
+                    self.item_state.select(i);
+                    self.load_preview();
+                }
+            }
+        }
+    }
+
+    fn key(&mut self, code: KeyCode) {
+        if self.prompt.is_some() {
+            return self.prompt_key(code);
+        }
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Tab
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::Char('h')
+            | KeyCode::Char('l') => {
+                self.pane = if self.pane == Pane::Entries && !self.items.is_empty() {
+                    Pane::Items
+                } else {
+                    Pane::Entries
+                };
+            }
+            KeyCode::Enter if self.pane == Pane::Entries && !self.items.is_empty() => {
+                self.pane = Pane::Items;
+                self.load_preview();
+            }
+            KeyCode::Enter => self.load_preview(),
+            KeyCode::Down | KeyCode::Char('j') => self.select(true),
+            KeyCode::Up | KeyCode::Char('k') => self.select(false),
+            KeyCode::Char('g') => {
+                self.reload();
+                self.message = HELP.to_owned();
+            }
+            KeyCode::Char('e') if self.entry().is_some() => {
+                self.prompt = Some(Prompt::Export(".".to_owned()))
+            }
+            KeyCode::Char('d') if self.entry().is_some() => self.prompt = Some(Prompt::Delete),
+            KeyCode::Char('r') if self.pane == Pane::Items => {
+                if let Some(item) = self.item() {
+                    self.prompt = Some(Prompt::Rename(item.name.clone()))
+                }
+            }
+            KeyCode::Char('s') => self.prompt = Some(Prompt::Sync(String::new())),
+            _ => {}
+        }
+    }
+
+    fn prompt_key(&mut self, code: KeyCode) {
+        let prompt = self.prompt.take().unwrap();
+        if let Prompt::Delete = prompt {
+            if let KeyCode::Char('y') = code {
+                self.delete();
+            } else {
+                self.message = "not deleted".to_owned();
+            }
+            return;
+        }
+        let mut prompt = prompt;
+        let text = match &mut prompt {
+            Prompt::Export(t) | Prompt::Rename(t) | Prompt::Sync(t) => t,
+            Prompt::Delete => unreachable!(),
+        };
+        match code {
+            KeyCode::Esc => {
+                self.message = HELP.to_owned();
+                return;
+            }
+            KeyCode::Enter => {
+                let text = text.clone();
+                return match prompt {
+                    Prompt::Export(_) => self.export(&text),
+                    Prompt::Rename(_) => self.rename(&text),
+                    Prompt::Sync(_) => self.sync(&text),
+                    Prompt::Delete => unreachable!(),
+                };
+            }
+            KeyCode::Backspace => {
+                text.pop();
+            }
+            KeyCode::Char(c) => text.push(c),
+            _ => {}
+        }
+        self.prompt = Some(prompt);
+    }
+
+    /// delete the selected item, or the selected entry from the entries pane
+    fn delete(&mut self) {
+        let entry = match self.entry() {
+            Some(entry) => entry.to_owned(),
+            None => return,
+        };
+        let item = match self.pane {
+            Pane::Items => self.item().map(|i| i.name.clone()),
+            Pane::Entries => None,
+        };
+        match self.db.delete(&entry, item.as_deref()) {
+            Ok(()) => {
+                self.message = match &item {
+                    Some(item) => format!("deleted {}/{}, it is in the trash", entry, item),
+                    None => format!("deleted {}, its items are in the trash", entry),
+                };
+                if item.is_none() {
+                    self.pane = Pane::Entries;
+                }
+                self.reload();
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// export the selected item to `dir`, or the whole entry from the entries pane
+    fn export(&mut self, dir: &str) {
+        let entry = match self.entry() {
+            Some(entry) => entry.to_owned(),
+            None => return,
+        };
+        let dir = Path::new(dir);
+        match (&self.pane, self.item().map(|i| i.name.clone())) {
+            (Pane::Items, Some(item)) => {
+                let path = dir.join(&item);
+                let res = std::fs::File::create(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut f| {
+                        self.db
+                            .cat(&entry, &item, &mut f)
+                            .map_err(|e| e.to_string())?;
+                        f.flush().map_err(|e| e.to_string())
+                    });
+                self.message = match res {
+                    Ok(()) => format!("exported {}/{} to {}", entry, item, path.display()),
+                    Err(e) => format!("error: {}", e),
+                };
+            }
+            _ => match self.db.export_to_dir(dir, &entry) {
+                Ok(()) => self.message = format!("exported {} to {}", entry, dir.display()),
+                Err(e) => self.fail(e),
+            },
+        }
+    }
+
+    fn rename(&mut self, new_name: &str) {
+        let (entry, item) = match (self.entry(), self.item()) {
+            (Some(entry), Some(item)) => (entry.to_owned(), item.name.clone()),
+            _ => return,
+        };
+        if new_name.is_empty() || new_name == item {
+            self.message = "not renamed".to_owned();
+            return;
+        }
+        match self.db.rename(&entry, &item, new_name) {
+            Ok(()) => {
+                self.message = format!("renamed {}/{} to {}", entry, item, new_name);
+                self.load_items();
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn sync(&mut self, message: &str) {
+        let message = if message.is_empty() {
+            None
+        } else {
+            Some(message)
+        };
+        match self.db.sync(message) {
+            Ok(()) => {
+                self.message = "synced".to_owned();
+                self.reload();
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn draw(&mut self, f: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(f.area());
+        let [entries, items, preview] = Layout::horizontal([
+            Constraint::Percentage(20),
+            Constraint::Percentage(45),
+            Constraint::Percentage(35),
+        ])
+        .areas(main);
+        let (entries_style, items_style) = match self.pane {
+            Pane::Entries => (Style::new().bold(), Style::new()),
+            Pane::Items => (Style::new(), Style::new().bold()),
+        };
+
+        let list = List::new(self.entries.iter().map(|e| e.as_str()))
+            .block(
+                Block::bordered()
+                    .title("Entries")
+                    .border_style(entries_style),
+            )
+            .highlight_style(Style::new().reversed());
+        f.render_stateful_widget(list, entries, &mut self.entry_state);
+
+        let rows = self.items.iter().map(|i| {
+            Row::new(vec![
+                i.name.clone(),
+                super::format_size(i.size),
+                super::format_size(i.stored),
+                i.checksum.clone().unwrap_or_default(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(2),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(Row::new(vec!["Item", "Size", "Stored", "Checksum"]).bold())
+        .block(Block::bordered().title("Items").border_style(items_style))
+        .row_highlight_style(Style::new().reversed());
+        f.render_stateful_widget(table, items, &mut self.item_state);
+
+        let para = Paragraph::new(self.preview.as_str())
+            .block(Block::bordered().title("Preview"))
+            .wrap(Wrap { trim: false });
+        f.render_widget(para, preview);
+
+        let line = match &self.prompt {
+            Some(Prompt::Export(t)) => format!("export to dir: {}", t),
+            Some(Prompt::Rename(t)) => format!("rename to: {}", t),
+            Some(Prompt::Sync(t)) => format!("sync message: {}", t),
+            Some(Prompt::Delete) => match (&self.pane, self.item()) {
+                (Pane::Items, Some(item)) => format!("delete {}? (y/n)", item.name),
+                _ => format!("delete entry {}? (y/n)", self.entry().unwrap_or("")),
+            },
+            None => self.message.clone(),
+        };
+        f.render_widget(Paragraph::new(line), status);
+    }
+}
+
+/// Limited keep the first `PREVIEW_MAX` bytes written, and fail after
+struct Limited(Vec<u8>);
+
+impl Write for Limited {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = PREVIEW_MAX - self.0.len();
+        if room == 0 {
+            return Err(io::Error::other("preview is full"));
+        }
+        let n = buf.len().min(room);
+        self.0.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// preview_text is `content` if it looks like text, else a note of its size
+fn preview_text(content: &[u8]) -> String {
+    // a multibyte char may be cut at the end of the preview
+    let text = match std::str::from_utf8(content) {
+        Ok(text) => Some(text),
+        Err(e) if content.len() - e.valid_up_to() < 4 && e.error_len().is_none() => {
+            Some(std::str::from_utf8(&content[..e.valid_up_to()]).unwrap())
+        }
+        Err(_) => None,
+    };
+    match text {
+        Some(text) if !text.contains('\0') => text.to_owned(),
+        _ if content.len() >= PREVIEW_MAX => "<binary>".to_owned(),
+        _ => format!("<binary, {} bytes>", content.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview() {
+        assert_eq!(preview_text(b"hello\n"), "hello\n");
+        assert_eq!(preview_text("h\u{e9}".as_bytes()), "h\u{e9}");
+        assert_eq!(preview_text(&"h\u{e9}".as_bytes()[..2]), "h");
+        assert_eq!(preview_text(b"\0\x01\xff"), "<binary, 3 bytes>");
+    }
+}
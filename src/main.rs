@@ -4,7 +4,12 @@ extern crate db;
 extern crate dirs;
 extern crate front;
 use clap::{App, Arg, SubCommand};
+use db::cipher::Cipher;
+use db::codec::Compression;
 use db::docker::ImageDrive;
+use db::hash::Algorithm;
+use db::metacache::MetaCache;
+use db::Conflict;
 
 fn main() {
     let matches = App::new("ImageDrive")
@@ -18,6 +23,37 @@ fn main() {
                 .help("Sets a custom config file, default: ~/.imagedrive")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("quiet")
+                .help(
+                    "don't show progress of transfers. Copies show bytes, rate and ETA, \
+                     pushes and pulls only the status of each layer",
+                )
+                .short("q")
+                .long("quiet")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("offline")
+                .help("ls, find and status read the local cache of the index, without docker")
+                .long("offline")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("no-cache")
+                .help("hash every file again, instead of reusing hashes in ~/.cache/imagedrive")
+                .long("no-cache")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .help("threads hashing files and reading checksums, default: number of cpus")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .takes_value(true)
+                .global(true),
+        )
         .arg(
             Arg::with_name("output")
                 .help("Sets an optional output file")
@@ -32,12 +68,55 @@ fn main() {
                         .index(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("find")
+                .about("find items of every entry by name")
+                .arg(
+                    Arg::with_name("pattern")
+                        .help("part of item name, or a glob with * and ?")
+                        .required(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("put")
                 .about("put host file to imagedrive")
                 .arg(Arg::with_name("entry").help("entry name").required(true))
-                .arg(Arg::with_name("file").help("file path").required(true))
-                .arg(Arg::with_name("name").help("rename file")),
+                .arg(
+                    Arg::with_name("file")
+                        .help("file paths")
+                        .multiple(true)
+                        .required_unless("from-list"),
+                )
+                .arg(
+                    Arg::with_name("from-list")
+                        .help("file with one path per line to put")
+                        .long("from-list")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .help("rename file, only when one file is put")
+                        .short("n")
+                        .long("name")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("overwrite")
+                        .help("replace an item of the same name")
+                        .long("overwrite")
+                        .conflicts_with_all(&["no-clobber", "rename-on-conflict"]),
+                )
+                .arg(
+                    Arg::with_name("no-clobber")
+                        .help("keep an item of the same name and fail, the default")
+                        .long("no-clobber")
+                        .conflicts_with("rename-on-conflict"),
+                )
+                .arg(
+                    Arg::with_name("rename-on-conflict")
+                        .help("put under a free name if an item of the same name exists")
+                        .long("rename-on-conflict"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("export")
@@ -45,6 +124,28 @@ fn main() {
                 .arg(Arg::with_name("entry").help("entry name").required(true))
                 .arg(Arg::with_name("dir").help("dst dir path").required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("mirror")
+                .about("make entry the same as host dir, or the reverse with --pull")
+                .arg(Arg::with_name("dir").help("host dir path").required(true))
+                .arg(Arg::with_name("entry").help("entry name").required(true))
+                .arg(
+                    Arg::with_name("pull")
+                        .help("make host dir the same as entry")
+                        .long("pull"),
+                )
+                .arg(
+                    Arg::with_name("delete")
+                        .help("delete items which are not in the source")
+                        .long("delete"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("print content of item")
+                .arg(Arg::with_name("entry").help("entry name").required(true))
+                .arg(Arg::with_name("item").help("item name").required(true)),
+        )
         .subcommand(
             SubCommand::with_name("sync")
                 .about("sync localDB with remoteDB")
@@ -53,27 +154,170 @@ fn main() {
                         .help("sync remoteDB to localDB")
                         .short("r")
                         .long("remote"),
+                )
+                .arg(
+                    Arg::with_name("message")
+                        .help("message of the new revision")
+                        .short("m")
+                        .long("message")
+                        .takes_value(true),
                 ),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("remove entry or file")
                 .arg(Arg::with_name("entry").help("entry name").required(true))
-                .arg(Arg::with_name("file").help("file name")),
+                .arg(Arg::with_name("file").help("file name"))
+                .arg(
+                    Arg::with_name("recursive")
+                        .help("remove the whole entry")
+                        .short("r")
+                        .long("recursive"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("status").about("show changes not synced yet"))
+        .subcommand(SubCommand::with_name("ui").about("browse the drive in the terminal"))
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("serve the drive over http to clients with its token")
+                .arg(
+                    Arg::with_name("listen")
+                        .help("address to listen at")
+                        .long("listen")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8080"),
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .help("token clients send as `Authorization: Bearer <token>`, random if not given")
+                        .long("token")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("mount the drive as a filesystem, until it is unmounted")
+                .arg(
+                    Arg::with_name("mountpoint")
+                        .help("empty dir to mount at")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("rw")
+                        .help("allow writes, staged in localDB until sync")
+                        .long("rw"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("find containers leaked by imagedrive")
+                .arg(
+                    Arg::with_name("prune")
+                        .help("remove orphaned containers")
+                        .long("prune"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .alias("verify")
+                .about("check items against their checksums")
+                .arg(
+                    Arg::with_name("repair")
+                        .help("rebuild missing checksums and remove orphaned ones")
+                        .long("repair"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("log").about("list revisions of the drive"))
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("show changes of a revision")
+                .arg(Arg::with_name("rev").help("revision").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("checkout")
+                .about("make localDB the same as a revision")
+                .arg(Arg::with_name("rev").help("revision").required(true))
+                .arg(
+                    Arg::with_name("force")
+                        .help("drop changes not synced")
+                        .short("f")
+                        .long("force"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("named snapshots of the drive")
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("tag last synced revision as snapshot and push it")
+                        .arg(Arg::with_name("name").help("snapshot name").required(true)),
+                )
+                .subcommand(SubCommand::with_name("ls").about(
+                    "list snapshots on this host, those pushed from other hosts are not \
+                     listed but can be restored by name",
+                ))
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("make localDB the same as snapshot, pulled if not on this host")
+                        .arg(Arg::with_name("name").help("snapshot name").required(true))
+                        .arg(
+                            Arg::with_name("force")
+                                .help("drop changes not synced")
+                                .short("f")
+                                .long("force"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trash")
+                .about("items deleted by rm")
+                .subcommand(SubCommand::with_name("ls").about("list deleted items"))
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("move a deleted item back to its entry")
+                        .arg(
+                            Arg::with_name("item")
+                                .help("<entry>/<item>")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("empty")
+                        .about("remove deleted items for good")
+                        .arg(
+                            Arg::with_name("older-than")
+                                .help("only items deleted before, e.g. 30d, 12h")
+                                .long("older-than")
+                                .takes_value(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("revert")
+                .about("undo changes of a revision in localDB")
+                .arg(Arg::with_name("rev").help("revision").required(true))
+                .arg(
+                    Arg::with_name("force")
+                        .help("drop changes not synced")
+                        .short("f")
+                        .long("force"),
+                ),
         )
         .get_matches();
 
-    let mut config_path;
+    #[allow(clippy::needless_late_init)]
+    let config_path;
     if matches.is_present("config") {
         config_path = Some(std::path::Path::new(matches.value_of("config").unwrap()).to_path_buf());
     } else {
         let home = dirs::home_dir().unwrap();
         config_path = Some(std::path::Path::new(&home).join(".imagedrive"));
     }
+    #[allow(clippy::manual_inspect)]
     let config_path = config_path.map(|p| {
         if !p.exists() {
             let errstr = format!("config file: '{:?}' not exists", p.display());
-            panic!(errstr);
+            panic!("{}", errstr);
         }
         p
     });
@@ -84,49 +328,292 @@ fn main() {
     let server = &cfg.server;
     let password = &cfg.password;
     let image_name = &cfg.image_name;
+    let cipher = match (&cfg.encryption_key, &cfg.keyfile) {
+        (Some(key), _) => Some(Cipher::from_hex(key, cfg.encrypt_names)),
+        (None, Some(keyfile)) => Some(Cipher::from_keyfile(keyfile, cfg.encrypt_names)),
+        (None, None) => None,
+    }
+    .map(|cipher| cipher.unwrap_or_else(|e| panic!("bad encryption key: {:?}", e)));
+    let compression = cfg.compression.as_ref().map(|c| {
+        c.parse::<Compression>()
+            .unwrap_or_else(|e| panic!("bad compression: {}", e))
+    });
+    let algorithm = cfg.hash.as_ref().map(|h| {
+        h.parse::<Algorithm>()
+            .unwrap_or_else(|e| panic!("bad hash: {}", e))
+    });
+    // global, so it may come after the subcommand
+    let quiet = matches.is_present("quiet")
+        || matches
+            .subcommand()
+            .1
+            .is_some_and(|m| m.is_present("quiet"));
+    let offline = matches.is_present("offline")
+        || matches
+            .subcommand()
+            .1
+            .is_some_and(|m| m.is_present("offline"));
+    let no_cache = matches.is_present("no-cache")
+        || matches
+            .subcommand()
+            .1
+            .is_some_and(|m| m.is_present("no-cache"));
+    let jobs = matches
+        .value_of("jobs")
+        .or_else(|| matches.subcommand().1.and_then(|m| m.value_of("jobs")))
+        .map(|j| {
+            j.parse::<usize>()
+                .unwrap_or_else(|e| panic!("bad jobs '{}': {}", j, e))
+        })
+        .or(cfg.jobs);
+    let drive = {
+        let mut drive = ImageDrive::new(image_name, server, username, password);
+        if let Some(ref cipher) = cipher {
+            drive = drive.with_cipher(cipher.clone());
+        }
+        if let Some(compression) = compression {
+            drive = drive.with_compression(compression);
+        }
+        if let Some(progress) = front::progress(quiet) {
+            drive = drive.with_progress(progress);
+        }
+        if let Some(threshold) = cfg.chunk_threshold {
+            drive = drive.with_chunk_threshold(threshold);
+        }
+        if let Some(algorithm) = algorithm {
+            drive = drive.with_algorithm(algorithm);
+        }
+        if let Some(jobs) = jobs {
+            drive = drive.with_jobs(jobs);
+        }
+        if let Some(cache) = dirs::cache_dir().filter(|_| !no_cache) {
+            drive = drive.with_hash_cache(&cache.join("imagedrive").join("hashes"));
+        }
+        let index = dirs::cache_dir().map(|cache| cache.join("imagedrive").join("index.db"));
+        if let Some(cache) = index.and_then(|path| MetaCache::open(&path).ok()) {
+            drive = drive.with_meta_cache(cache);
+        }
+        drive
+    };
 
-    if let Some(matches) = matches.subcommand_matches("ls") {
+    // true if the command succeeded with the work container, so the cached index is refreshed
+    let refresh = if let Some(matches) = matches.subcommand_matches("ls") {
         if matches.is_present("entry") {
             front::list_entry_item(
-                &ImageDrive::new(image_name, server, username, password),
+                &drive,
                 matches.value_of("entry").unwrap(),
-            );
+                offline,
+            )
         } else {
-            front::list_entry(&ImageDrive::new(image_name, server, username, password));
+            front::list_entry(&drive, offline)
         }
+    } else if let Some(matches) = matches.subcommand_matches("find") {
+        front::find(&drive, matches.value_of("pattern").unwrap(), offline)
     } else if let Some(matches) = matches.subcommand_matches("put") {
         let entry = matches.value_of("entry").unwrap();
-        let filepath = matches.value_of("file").unwrap();
+        let mut files: Vec<String> = matches
+            .values_of("file")
+            .map(|files| files.map(|f| f.to_owned()).collect())
+            .unwrap_or_default();
+        if let Some(list) = matches.value_of("from-list") {
+            let content = std::fs::read_to_string(list)
+                .unwrap_or_else(|e| panic!("read list '{}' fail: {}", list, e));
+            files.extend(
+                content
+                    .lines()
+                    .map(|l| l.trim())
+                    .filter(|l| !l.is_empty())
+                    .map(|l| l.to_owned()),
+            );
+        }
+        // `put <entry> <file> <name>` before --name: a missing second path is not taken as a name
+        if !matches.is_present("name")
+            && !matches.is_present("from-list")
+            && files.len() == 2
+            && !std::path::Path::new(&files[1]).exists()
+        {
+            panic!(
+                "no such file: '{}', use --name '{}' to put '{}' under that name",
+                files[1], files[1], files[0]
+            );
+        }
         let rename = matches.value_of("name");
-        front::put(
-            &ImageDrive::new(image_name, server, username, password),
-            entry,
-            filepath,
-            rename,
-        );
+        let conflict = if matches.is_present("overwrite") {
+            Conflict::Overwrite
+        } else if matches.is_present("rename-on-conflict") {
+            Conflict::Rename
+        } else {
+            Conflict::NoClobber
+        };
+        if files.len() == 1 {
+            front::put(
+                &drive,
+                entry,
+                &files[0],
+                rename,
+                conflict,
+            )
+        } else {
+            if rename.is_some() {
+                panic!("--name can only be used when one file is put");
+            }
+            front::put_many(
+                &drive,
+                entry,
+                &files,
+                conflict,
+            )
+        }
     } else if let Some(matches) = matches.subcommand_matches("export") {
         let entry = matches.value_of("entry").unwrap();
         let filepath = matches.value_of("dir").unwrap();
         front::export(
-            &ImageDrive::new(image_name, server, username, password),
+            &drive,
             entry,
             filepath,
         );
+        false
+    } else if let Some(matches) = matches.subcommand_matches("mirror") {
+        front::mirror(
+            &drive,
+            matches.value_of("dir").unwrap(),
+            matches.value_of("entry").unwrap(),
+            matches.is_present("pull"),
+            matches.is_present("delete"),
+        )
+    } else if let Some(matches) = matches.subcommand_matches("cat") {
+        front::cat(
+            &drive,
+            matches.value_of("entry").unwrap(),
+            matches.value_of("item").unwrap(),
+        );
+        false
     } else if let Some(matches) = matches.subcommand_matches("sync") {
         front::sync(
-            &ImageDrive::new(image_name, server, username, password),
+            &drive,
             matches.is_present("from_remote"),
-        );
+            matches.value_of("message"),
+        )
     } else if let Some(matches) = matches.subcommand_matches("rm") {
         let entry = matches.value_of("entry").unwrap();
         let file = matches.value_of("file");
         front::rm(
-            &ImageDrive::new(image_name, server, username, password),
+            &drive,
             entry,
             file,
+            matches.is_present("recursive"),
+        )
+    } else if matches.subcommand_matches("status").is_some() {
+        front::status(&drive, offline)
+    } else if matches.subcommand_matches("ui").is_some() {
+        if let Err(e) = front::ui(&drive) {
+            println!("ui failed: {}", e);
+        }
+        false
+    } else if let Some(matches) = matches.subcommand_matches("serve") {
+        if let Err(e) = front::serve(
+            &drive,
+            matches.value_of("listen").unwrap(),
+            matches.value_of("token"),
+        ) {
+            println!("serve failed: {}", e);
+        }
+        false
+    } else if let Some(matches) = matches.subcommand_matches("mount") {
+        let mountpoint = std::path::Path::new(matches.value_of("mountpoint").unwrap());
+        if let Err(e) = front::mount(&drive, mountpoint, matches.is_present("rw")) {
+            println!("mount failed: {}", e);
+        }
+        false
+    } else if let Some(matches) = matches.subcommand_matches("doctor") {
+        front::doctor(
+            &drive,
+            matches.is_present("prune"),
         );
+        false
+    } else if let Some(matches) = matches.subcommand_matches("fsck") {
+        front::fsck(
+            &drive,
+            matches.is_present("repair"),
+        )
+    } else if matches.subcommand_matches("log").is_some() {
+        front::log(&drive);
+        false
+    } else if let Some(matches) = matches.subcommand_matches("show") {
+        front::show(
+            &drive,
+            parse_rev(matches.value_of("rev").unwrap()),
+        );
+        false
+    } else if let Some(matches) = matches.subcommand_matches("checkout") {
+        front::checkout(
+            &drive,
+            parse_rev(matches.value_of("rev").unwrap()),
+            matches.is_present("force"),
+        )
+    } else if let Some(matches) = matches.subcommand_matches("revert") {
+        front::revert(
+            &drive,
+            parse_rev(matches.value_of("rev").unwrap()),
+            matches.is_present("force"),
+        )
+    } else if let Some(matches) = matches.subcommand_matches("snapshot") {
+        let db = &drive;
+        if let Some(matches) = matches.subcommand_matches("create") {
+            front::snapshot_create(db, matches.value_of("name").unwrap());
+            false
+        } else if let Some(matches) = matches.subcommand_matches("restore") {
+            front::snapshot_restore(
+                db,
+                matches.value_of("name").unwrap(),
+                matches.is_present("force"),
+            )
+        } else {
+            front::snapshot_list(db);
+            false
+        }
+    } else if let Some(matches) = matches.subcommand_matches("trash") {
+        let db = &drive;
+        if let Some(matches) = matches.subcommand_matches("restore") {
+            let path = matches.value_of("item").unwrap();
+            let (entry, item) = path
+                .find('/')
+                .map(|pos| (&path[..pos], &path[pos + 1..]))
+                .unwrap_or_else(|| panic!("'{}' is not <entry>/<item>", path));
+            front::trash_restore(db, entry, item)
+        } else if let Some(matches) = matches.subcommand_matches("empty") {
+            front::trash_empty(db, matches.value_of("older-than").map(parse_age))
+        } else {
+            front::trash_list(db);
+            false
+        }
     } else {
         // default
-        front::list_entry(&ImageDrive::new(image_name, server, username, password));
+        front::list_entry(&drive, offline)
+    };
+    // keep the cached index fresh for --offline, it is best effort
+    if refresh && !offline {
+        let _ = drive.refresh_cache();
+    }
+}
+
+fn parse_rev(rev: &str) -> u32 {
+    rev.parse()
+        .unwrap_or_else(|_| panic!("revision: '{}' is not a number", rev))
+}
+
+/// parse_age parse ages like `30d`, `12h`, `15m` or `60s` to seconds
+fn parse_age(age: &str) -> u64 {
+    let pos = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (n, unit) = age.split_at(pos);
+    let n: u64 = n
+        .parse()
+        .unwrap_or_else(|_| panic!("age: '{}' is not like 30d", age));
+    match unit {
+        "d" => n * 24 * 60 * 60,
+        "h" => n * 60 * 60,
+        "m" => n * 60,
+        "s" | "" => n,
+        _ => panic!("age: '{}' has unknown unit, use d, h, m or s", age),
     }
 }